@@ -1,12 +1,17 @@
 #![cfg_attr(feature = "fail-on-warnings", deny(warnings))]
 
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use strum_macros::{AsRefStr, EnumString};
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, EnumString, AsRefStr)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, EnumString, AsRefStr,
+)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum LogLevel {
@@ -24,6 +29,13 @@ pub enum LogComponent {
     Real(f64),
     String(String),
     Boolean(bool),
+    /// Milliseconds since the Unix epoch. Serialized as a number, displayed as ISO-8601.
+    ///
+    /// The type is lost over JSON: a serialized timestamp is read back as an
+    /// [`Integer`](Self::Integer) or [`UInteger`](Self::UInteger).
+    Timestamp(i64),
+    Array(Vec<LogComponent>),
+    Object(BTreeMap<String, LogComponent>),
     Undefined,
     Null,
 }
@@ -36,6 +48,10 @@ impl Display for LogComponent {
             LogComponent::Real(value) => f.write_fmt(format_args!("{value}")),
             LogComponent::String(value) => f.write_fmt(format_args!("{value}")),
             LogComponent::Boolean(value) => f.write_fmt(format_args!("{value}")),
+            LogComponent::Timestamp(value) => f.write_str(&iso8601_from_millis(*value)),
+            LogComponent::Array(_) | LogComponent::Object(_) => {
+                f.write_str(&serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+            }
             LogComponent::Undefined => f.write_str("undefined"),
             LogComponent::Null => f.write_str("null"),
         }
@@ -84,6 +100,43 @@ impl From<String> for LogComponent {
     }
 }
 
+impl From<std::time::SystemTime> for LogComponent {
+    fn from(value: std::time::SystemTime) -> Self {
+        LogComponent::Timestamp(match value.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => since.as_millis() as i64,
+            Err(err) => -(err.duration().as_millis() as i64),
+        })
+    }
+}
+
+impl From<Value> for LogComponent {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => LogComponent::Null,
+            Value::Bool(value) => LogComponent::Boolean(value),
+            Value::Number(value) => {
+                if let Some(value) = value.as_u64() {
+                    LogComponent::UInteger(value as usize)
+                } else if let Some(value) = value.as_i64() {
+                    LogComponent::Integer(value as isize)
+                } else {
+                    LogComponent::Real(value.as_f64().unwrap_or_default())
+                }
+            }
+            Value::String(value) => LogComponent::String(value),
+            Value::Array(values) => {
+                LogComponent::Array(values.into_iter().map(Into::into).collect())
+            }
+            Value::Object(object) => LogComponent::Object(
+                object
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 impl From<LogComponent> for String {
     fn from(value: LogComponent) -> Self {
         value.to_string()
@@ -101,6 +154,9 @@ impl Serialize for LogComponent {
             LogComponent::Real(value) => serializer.serialize_f64(*value),
             LogComponent::String(value) => serializer.serialize_str(value),
             LogComponent::Boolean(value) => serializer.serialize_bool(*value),
+            LogComponent::Timestamp(value) => serializer.serialize_i64(*value),
+            LogComponent::Array(values) => values.serialize(serializer),
+            LogComponent::Object(object) => object.serialize(serializer),
             LogComponent::Undefined => serializer.serialize_none(),
             LogComponent::Null => serializer.serialize_none(),
         }
@@ -114,21 +170,7 @@ impl<'de> Deserialize<'de> for LogComponent {
     {
         let value: Value = Deserialize::deserialize(deserializer)?;
 
-        if value.is_u64() {
-            Ok(LogComponent::UInteger(value.as_u64().unwrap() as usize))
-        } else if value.is_i64() {
-            Ok(LogComponent::Integer(value.as_i64().unwrap() as isize))
-        } else if value.is_f64() {
-            Ok(LogComponent::Real(value.as_f64().unwrap()))
-        } else if value.is_string() {
-            Ok(LogComponent::String(value.as_str().unwrap().to_string()))
-        } else if value.is_boolean() {
-            Ok(LogComponent::Boolean(value.as_bool().unwrap()))
-        } else if value.is_null() {
-            Ok(LogComponent::Null)
-        } else {
-            Ok(LogComponent::Undefined)
-        }
+        Ok(value.into())
     }
 }
 
@@ -139,9 +181,11 @@ pub struct LogEntry<'a> {
     pub ip: &'a str,
     pub user_agent: &'a str,
     pub properties: Option<HashMap<String, LogComponent>>,
+    pub fields: Option<HashMap<String, LogComponent>>,
+    pub id: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LogEntryRequest {
     pub level: LogLevel,
@@ -150,6 +194,203 @@ pub struct LogEntryRequest {
     pub target: Option<String>,
     pub module_path: Option<String>,
     pub location: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_sorted_properties"
+    )]
     pub properties: Option<HashMap<String, LogComponent>>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_sorted_properties"
+    )]
+    pub fields: Option<HashMap<String, LogComponent>>,
+    /// A unique ID, e.g. a ULID, that writers can use to deduplicate retried entries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub audit: bool,
+}
+
+fn serialize_sorted_properties<S>(
+    value: &Option<HashMap<String, LogComponent>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match value {
+        Some(properties) => serializer.collect_map(properties.iter().collect::<BTreeMap<_, _>>()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogBatchMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_sha: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogBatchRequest {
+    pub meta: LogBatchMeta,
+    pub entries: Vec<LogEntryRequest>,
+}
+
+/// Filters for reading entries back from `GET /logs`, sent as query parameters
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_level: Option<LogLevel>,
+    /// Milliseconds since the Unix epoch, inclusive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<usize>,
+    /// Milliseconds since the Unix epoch, exclusive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<usize>,
+    /// Matches targets equal to this or nested under it, e.g. `app` matches `app::db`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl LogQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn min_level(mut self, value: LogLevel) -> Self {
+        self.min_level = Some(value);
+        self
+    }
+
+    pub fn from(mut self, value: usize) -> Self {
+        self.from = Some(value);
+        self
+    }
+
+    pub fn to(mut self, value: usize) -> Self {
+        self.to = Some(value);
+        self
+    }
+
+    pub fn target(mut self, value: impl Into<String>) -> Self {
+        self.target = Some(value.into());
+        self
+    }
+
+    pub fn limit(mut self, value: usize) -> Self {
+        self.limit = Some(value);
+        self
+    }
+
+    /// Whether the entry passes every filter except `limit`
+    pub fn matches(&self, entry: &LogEntryRequest) -> bool {
+        self.min_level.is_none_or(|x| entry.level >= x)
+            && self.from.is_none_or(|x| entry.ts >= x)
+            && self.to.is_none_or(|x| entry.ts < x)
+            && self.target.as_ref().is_none_or(|target| {
+                entry.target.as_ref().is_some_and(|x| {
+                    x.strip_prefix(target.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+                })
+            })
+    }
+}
+
+/// Encodes a single entry as JSON, the format clients send and the writer reads
+pub fn to_wire_bytes(entry: &LogEntryRequest) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::to_vec(entry)
+}
+
+/// Decodes a single entry encoded by [`to_wire_bytes`]
+pub fn from_wire_bytes(bytes: &[u8]) -> Result<LogEntryRequest, serde_json::Error> {
+    serde_json::from_slice(bytes)
+}
+
+pub fn iso8601_from_millis(millis: i64) -> String {
+    let secs = millis.div_euclid(1000);
+    let days = secs.div_euclid(86_400);
+    let secs_of_day = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        millis.rem_euclid(1000),
+    )
+}
+
+/// The `(year, month, day)` of a day counted from the Unix epoch, see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn timestamps_display_as_iso8601() {
+        let timestamp = LogComponent::Timestamp(1_700_000_000_123);
+
+        assert_eq!(timestamp.to_string(), "2023-11-14T22:13:20.123Z");
+        assert_eq!(
+            LogComponent::Timestamp(-1).to_string(),
+            "1969-12-31T23:59:59.999Z"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_read_back_from_json_as_integers() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let timestamp = LogComponent::from(time);
+
+        assert!(matches!(
+            timestamp,
+            LogComponent::Timestamp(1_700_000_000_123)
+        ));
+
+        let json = serde_json::to_string(&timestamp).unwrap();
+        assert_eq!(json, "1700000000123");
+
+        let read = serde_json::from_str::<LogComponent>(&json).unwrap();
+        assert!(matches!(read, LogComponent::UInteger(1_700_000_000_123)));
+
+        let read = serde_json::from_str::<LogComponent>("-1500").unwrap();
+        assert!(matches!(read, LogComponent::Integer(-1500)));
+    }
+
+    #[test]
+    fn times_before_the_epoch_are_negative_timestamps() {
+        let timestamp = LogComponent::from(UNIX_EPOCH - Duration::from_millis(1500));
+
+        assert!(matches!(timestamp, LogComponent::Timestamp(-1500)));
+    }
 }
@@ -0,0 +1,40 @@
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use free_log_client::{FreeLogLayer, LogsConfig};
+use tracing_subscriber::layer::SubscriberExt as _;
+
+fn on_event(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let layer = FreeLogLayer::new(LogsConfig::default());
+    let subscriber = tracing_subscriber::registry().with(layer.clone());
+
+    tracing::subscriber::with_default(subscriber, || {
+        c.bench_function("on_event message only", |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    tracing::info!("message only");
+                }
+                let elapsed = start.elapsed();
+                rt.block_on(layer.flush()).unwrap();
+                elapsed
+            })
+        });
+
+        c.bench_function("on_event with fields", |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for i in 0..iters {
+                    tracing::info!(iteration = i, flag = true, "with fields");
+                }
+                let elapsed = start.elapsed();
+                rt.block_on(layer.flush()).unwrap();
+                elapsed
+            })
+        });
+    });
+}
+
+criterion_group!(benches, on_event);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The gRPC types are generated from the proto file, with a vendored protoc so that building
+    // doesn't need one installed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/free_log.proto");
+
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+
+        tonic_build::configure().compile_protos(&["proto/free_log.proto"], &["proto"])?;
+    }
+
+    Ok(())
+}
@@ -1,12 +1,67 @@
-use std::sync::{Arc, LazyLock};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::AtomicUsize, Arc, LazyLock},
+    time::{Duration, Instant},
+};
 
-use tokio::{fs::File, io::BufWriter};
+use free_log_models::{LogComponent, LogEntryRequest};
+use parking_lot::Mutex;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt as _, BufWriter},
+};
 
-use crate::Level;
+use crate::{
+    format::EntryFormat, ApiWriterConfig, Compression, FileWriterConfig, FlushError, HttpProtocol,
+    WriterId,
+};
 
-pub(crate) type FileWriters = Arc<tokio::sync::Mutex<Option<Vec<(Level, BufWriter<File>)>>>>;
+pub(crate) type FileOutputWriters = Vec<(EntryFormat, BufWriter<File>)>;
 
-pub(crate) static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+pub(crate) type FileWriters =
+    Arc<tokio::sync::Mutex<Option<Vec<(FileWriterConfig, FileOutputWriters)>>>>;
+
+pub(crate) type ApiClients = Arc<tokio::sync::Mutex<Option<Vec<reqwest::Client>>>>;
+
+pub(crate) fn build_client(config: &ApiWriterConfig) -> Result<reqwest::Client, reqwest::Error> {
+    let mut builder = reqwest::Client::builder().dns_resolver(Arc::new(RetryingResolver::new(
+        config
+            .dns_retry_attempts
+            .unwrap_or(DEFAULT_DNS_RETRY_ATTEMPTS),
+        config.dns_cache_ttl.unwrap_or(DEFAULT_DNS_CACHE_TTL),
+    )));
+
+    match config.http_protocol {
+        HttpProtocol::Auto => {}
+        HttpProtocol::Http1 => {
+            builder = builder.http1_only();
+        }
+        HttpProtocol::Http2 => {
+            builder = builder.http2_prior_knowledge();
+        }
+    }
+
+    if let Some(max) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if let Some(timeout) = config.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    if let Some(interval) = config.tcp_keepalive {
+        builder = builder.tcp_keepalive(interval);
+    }
+    if let Some(address) = config.local_address {
+        builder = builder.local_address(address);
+    }
+
+    builder.build()
+}
 
 pub(crate) static RT: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
     tokio::runtime::Builder::new_multi_thread()
@@ -15,3 +70,449 @@ pub(crate) static RT: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
         .build()
         .unwrap()
 });
+
+const DEFAULT_DNS_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_DNS_CACHE_TTL: Duration = Duration::from_secs(30);
+const DNS_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+type DnsCache = Arc<Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>>;
+
+type LookupFuture = std::pin::Pin<
+    Box<dyn Future<Output = Result<Vec<SocketAddr>, std::io::Error>> + Send + 'static>,
+>;
+
+/// Resolves a host name to its addresses
+type Lookup = Arc<dyn Fn(String) -> LookupFuture + Send + Sync>;
+
+/// Retries failed lookups with backoff and falls back to the last known addresses, so a
+/// briefly unresolvable endpoint (e.g. at container startup) doesn't fail the flush
+pub(crate) struct RetryingResolver {
+    attempts: u32,
+    ttl: Duration,
+    cache: DnsCache,
+    lookup: Lookup,
+}
+
+impl RetryingResolver {
+    pub(crate) fn new(attempts: u32, ttl: Duration) -> Self {
+        Self::with_lookup(attempts, ttl, |host| {
+            Box::pin(
+                async move { Ok(tokio::net::lookup_host((host.as_str(), 0)).await?.collect()) },
+            )
+        })
+    }
+
+    /// Resolves with `lookup` rather than the system resolver
+    pub(crate) fn with_lookup(
+        attempts: u32,
+        ttl: Duration,
+        lookup: impl Fn(String) -> LookupFuture + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            lookup: Arc::new(lookup),
+        }
+    }
+}
+
+impl Resolve for RetryingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let attempts = self.attempts;
+        let ttl = self.ttl;
+        let cache = self.cache.clone();
+        let lookup = self.lookup.clone();
+
+        Box::pin(async move {
+            if let Some((resolved_at, addrs)) = cache.lock().get(&host) {
+                if resolved_at.elapsed() < ttl {
+                    return Ok(Box::new(addrs.clone().into_iter()) as Addrs);
+                }
+            }
+
+            let mut attempt = 0;
+
+            loop {
+                match lookup(host.clone()).await {
+                    Ok(addrs) => {
+                        cache
+                            .lock()
+                            .insert(host.clone(), (Instant::now(), addrs.clone()));
+                        return Ok(Box::new(addrs.into_iter()) as Addrs);
+                    }
+                    Err(err) => {
+                        attempt += 1;
+
+                        if attempt >= attempts {
+                            if let Some((_, addrs)) = cache.lock().get(&host) {
+                                return Ok(Box::new(addrs.clone().into_iter()) as Addrs);
+                            }
+                            return Err(err.into());
+                        }
+
+                        tokio::time::sleep(DNS_RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+tokio::task_local! {
+    pub(crate) static MDC: RefCell<HashMap<String, LogComponent>>;
+}
+
+/// An audit entry in the spool, with the writers that haven't confirmed it yet
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SpooledEntry {
+    pub pending: Vec<WriterId>,
+    pub entry: LogEntryRequest,
+}
+
+pub(crate) async fn read_spool(path: &Path) -> Result<Vec<SpooledEntry>, FlushError> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Replaces the spool with `entries`. They are written to a temporary file that is then renamed
+/// over the spool, so that a crash while writing never loses the previous spool.
+pub(crate) async fn write_spool(path: &Path, entries: &[SpooledEntry]) -> Result<(), FlushError> {
+    if entries.is_empty() {
+        return match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        };
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let mut writer = BufWriter::new(File::create(&temp_path).await?);
+
+    for entry in entries {
+        let mut body = serde_json::to_vec(entry)?;
+        body.push(b'\n');
+        writer.write_all(&body).await?;
+    }
+
+    writer.flush().await?;
+    writer.into_inner().sync_all().await?;
+
+    tokio::fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
+
+pub(crate) async fn with_timeout(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<(), FlushError>>,
+) -> Result<(), FlushError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or(Err(FlushError::Timeout(timeout))),
+        None => future.await,
+    }
+}
+
+/// Compresses a request body larger than the writer's threshold, returning the
+/// `Content-Encoding` it was compressed with, if any
+pub(crate) fn compress(
+    body: Vec<u8>,
+    config: &ApiWriterConfig,
+) -> Result<(Vec<u8>, Option<&'static str>), std::io::Error> {
+    if body.len() <= config.compression_threshold {
+        return Ok((body, None));
+    }
+
+    match config.compression {
+        Compression::None => Ok((body, None)),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            use std::io::Write as _;
+
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&body)?;
+            Ok((encoder.finish()?, Some("gzip")))
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok((zstd::encode_all(body.as_slice(), 0)?, Some("zstd"))),
+    }
+}
+
+pub(crate) async fn check_response(response: reqwest::Response) -> Result<(), FlushError> {
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(FlushError::Unsuccessful(
+            response
+                .text()
+                .await
+                .unwrap_or("(failed to get response text)".to_string()),
+        ));
+    }
+
+    let value: serde_json::Value = response.json().await?;
+
+    if !value
+        .get("success")
+        .and_then(|x| x.as_bool())
+        .unwrap_or_default()
+    {
+        return Err(FlushError::Unsuccessful(format!(
+            "Received unsuccessful response: {value:?}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Writes the entries to each of the file writer's outputs. The timeout, counted from
+/// `started`, is checked between entries rather than around the writes, which could cut a line
+/// in half.
+pub(crate) async fn write_file_entries(
+    writers: &mut FileOutputWriters,
+    entries: impl Iterator<Item = &LogEntryRequest>,
+    file_config: &FileWriterConfig,
+    timeout: Option<Duration>,
+    started: Instant,
+    skipped: &AtomicUsize,
+) -> Result<(), FlushError> {
+    for entry in entries {
+        if let Some(timeout) = timeout.filter(|x| started.elapsed() >= *x) {
+            for (_, writer) in writers.iter_mut() {
+                writer.flush().await?;
+            }
+
+            return Err(FlushError::Timeout(timeout));
+        }
+
+        let entry = &*file_config.property_keys.apply(entry);
+
+        for (format, writer) in writers.iter_mut() {
+            let body =
+                if file_config.large_integers_as_strings && !matches!(format, EntryFormat::Text) {
+                    crate::format::to_value(entry, *format, &file_config.level_mapping)
+                        .map(|x| crate::format::stringify_large_integers(x).to_string())
+                } else {
+                    crate::format::to_string(
+                        entry,
+                        *format,
+                        &file_config.level_mapping,
+                        file_config.float_precision,
+                    )
+                };
+
+            let body = match body {
+                Ok(body) => body,
+                Err(err) => {
+                    crate::skip_unserializable(skipped, &err);
+                    continue;
+                }
+            };
+            writer.write_all(&file_config.framing.frame(&body)).await?;
+        }
+    }
+
+    for (_, writer) in writers.iter_mut() {
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) const DUPLICATE_COUNT_PROPERTY: &str = "duplicateCount";
+
+/// Collapses exact duplicates (level, values and properties) within a batch into the first
+/// occurrence, recording how many times it was seen
+pub(crate) fn dedup_entries(entries: Vec<LogEntryRequest>) -> Vec<LogEntryRequest> {
+    let mut deduped: Vec<LogEntryRequest> = Vec::with_capacity(entries.len());
+    let mut counts: Vec<usize> = Vec::with_capacity(entries.len());
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for entry in entries {
+        if entry.audit {
+            deduped.push(entry);
+            counts.push(1);
+            continue;
+        }
+
+        match crate::entry_key(&entry) {
+            Some(key) => match seen.get(&key) {
+                Some(index) => {
+                    counts[*index] += 1;
+                }
+                None => {
+                    seen.insert(key, deduped.len());
+                    deduped.push(entry);
+                    counts.push(1);
+                }
+            },
+            None => {
+                deduped.push(entry);
+                counts.push(1);
+            }
+        }
+    }
+
+    for (entry, count) in deduped.iter_mut().zip(counts) {
+        if count > 1 {
+            entry
+                .properties
+                .get_or_insert_with(HashMap::new)
+                .insert(DUPLICATE_COUNT_PROPERTY.to_string(), count.into());
+        }
+    }
+
+    deduped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr as _, sync::atomic::Ordering};
+
+    use super::*;
+
+    /// A resolver whose lookups fail while `failing` is set, counting every lookup
+    fn resolver(
+        attempts: u32,
+        ttl: Duration,
+        failing: Arc<std::sync::atomic::AtomicBool>,
+        lookups: Arc<AtomicUsize>,
+    ) -> RetryingResolver {
+        RetryingResolver::with_lookup(attempts, ttl, move |_| {
+            let failing = failing.load(Ordering::SeqCst);
+            let lookup = lookups.fetch_add(1, Ordering::SeqCst) + 1;
+
+            Box::pin(async move {
+                if failing {
+                    Err(std::io::Error::other("unresolvable"))
+                } else {
+                    Ok(vec![SocketAddr::from(([10, 0, 0, lookup as u8], 0))])
+                }
+            })
+        })
+    }
+
+    async fn resolve(resolver: &RetryingResolver) -> Result<Vec<SocketAddr>, String> {
+        resolver
+            .resolve(Name::from_str("writer.internal").unwrap())
+            .await
+            .map(Iterator::collect)
+            .map_err(|x| x.to_string())
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_retried_until_the_attempts_run_out() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = resolver(2, Duration::ZERO, failing, lookups.clone());
+
+        assert_eq!(resolve(&resolver).await, Err("unresolvable".to_string()));
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fresh_addresses_are_served_from_the_cache() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = resolver(1, Duration::from_secs(60), failing, lookups.clone());
+
+        let first = resolve(&resolver).await.unwrap();
+
+        assert_eq!(resolve(&resolver).await.unwrap(), first);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_addresses_are_used_when_the_lookup_fails() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = resolver(2, Duration::ZERO, failing.clone(), lookups.clone());
+
+        let first = resolve(&resolver).await.unwrap();
+        failing.store(true, Ordering::SeqCst);
+
+        assert_eq!(resolve(&resolver).await.unwrap(), first);
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+        failing.store(false, Ordering::SeqCst);
+
+        assert_ne!(resolve(&resolver).await.unwrap(), first);
+    }
+
+    /// The first bytes a client built for `protocol` sends to a plain-text listener
+    async fn preface(protocol: HttpProtocol) -> String {
+        use tokio::io::AsyncReadExt as _;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ApiWriterConfig::builder()
+            .api_url(format!("http://{}", listener.local_addr().unwrap()))
+            .http_protocol(protocol)
+            .build()
+            .unwrap();
+        let client = build_client(&config).unwrap();
+        let request = tokio::spawn(client.post(format!("{}/logs", config.api_url)).send());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut data = vec![0; 24];
+        stream.read_exact(&mut data).await.unwrap();
+        request.abort();
+
+        String::from_utf8_lossy(&data).into_owned()
+    }
+
+    #[tokio::test]
+    async fn clients_speak_the_configured_http_protocol() {
+        assert!(preface(HttpProtocol::Auto)
+            .await
+            .starts_with("POST /logs HTTP/1.1\r\n"));
+        assert!(preface(HttpProtocol::Http1)
+            .await
+            .starts_with("POST /logs HTTP/1.1\r\n"));
+        assert_eq!(
+            preface(HttpProtocol::Http2).await,
+            "PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"
+        );
+    }
+
+    /// Linux routes all of 127.0.0.0/8 to loopback, so a second local address is available
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn clients_connect_from_the_configured_local_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ApiWriterConfig::builder()
+            .api_url(format!("http://{}", listener.local_addr().unwrap()))
+            .local_address("127.0.0.2")
+            .build()
+            .unwrap();
+        let client = build_client(&config).unwrap();
+        let request = tokio::spawn(client.post(format!("{}/logs", config.api_url)).send());
+
+        let (_stream, peer) = listener.accept().await.unwrap();
+        request.abort();
+
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+    }
+
+    #[test]
+    fn an_invalid_local_address_fails_the_build() {
+        assert!(matches!(
+            ApiWriterConfig::builder()
+                .api_url("http://localhost")
+                .local_address("eth0")
+                .build(),
+            Err(crate::BuildApiWriterConfigError::InvalidLocalAddress(x)) if x == "eth0"
+        ));
+    }
+}
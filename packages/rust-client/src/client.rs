@@ -0,0 +1,156 @@
+use free_log_models::{LogEntryRequest, LogQuery};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Unsuccessful: {0}")]
+    Unsuccessful(String),
+}
+
+#[derive(Deserialize)]
+struct QueryResponse {
+    success: bool,
+    entries: Vec<LogEntryRequest>,
+}
+
+/// Reads entries back from a writer's `GET /logs` endpoint, e.g. for CLIs and dashboards
+#[derive(Debug, Clone)]
+pub struct FreeLogClient {
+    api_url: String,
+    user_agent: String,
+    client: reqwest::Client,
+}
+
+impl FreeLogClient {
+    pub fn new(api_url: impl Into<String>) -> Self {
+        Self {
+            api_url: api_url.into(),
+            user_agent: "free_log_rust_client".to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn user_agent(mut self, value: impl Into<String>) -> Self {
+        self.user_agent = value.into();
+        self
+    }
+
+    pub async fn query(&self, query: LogQuery) -> Result<Vec<LogEntryRequest>, QueryError> {
+        let response = self
+            .client
+            .get(format!("{}/logs", self.api_url))
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .query(&query)
+            .send()
+            .await?;
+
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(QueryError::Unsuccessful(
+                response
+                    .text()
+                    .await
+                    .unwrap_or("(failed to get response text)".to_string()),
+            ));
+        }
+
+        let response: QueryResponse = response.json().await?;
+
+        if !response.success {
+            return Err(QueryError::Unsuccessful(
+                "Received unsuccessful response".to_string(),
+            ));
+        }
+
+        Ok(response.entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use free_log_models::LogLevel;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+
+    /// Answers a single request with `status` and `body`, returning the request's first line
+    async fn serve_once(
+        status: u16,
+        body: &'static str,
+    ) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut data = vec![];
+            let mut chunk = [0; 4096];
+
+            while !data.windows(4).any(|x| x == b"\r\n\r\n") {
+                let read = stream.read(&mut chunk).await.unwrap();
+                data.extend_from_slice(&chunk[..read]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 {status} Status\r\ncontent-type: application/json\r\n\
+                 content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            String::from_utf8_lossy(&data)
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        });
+
+        (url, request)
+    }
+
+    #[tokio::test]
+    async fn queries_send_their_filters_and_return_the_entries() {
+        let (url, request) = serve_once(
+            200,
+            concat!(
+                r#"{"success":true,"entries":["#,
+                r#"{"level":"ERROR","ts":5,"values":["failed"],"target":"app::db"}"#,
+                "]}",
+            ),
+        )
+        .await;
+
+        let entries = FreeLogClient::new(url)
+            .query(
+                LogQuery::new()
+                    .min_level(LogLevel::Warn)
+                    .from(1)
+                    .target("app")
+                    .limit(10),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            request.await.unwrap(),
+            "GET /logs?minLevel=WARN&from=1&target=app&limit=10 HTTP/1.1"
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].level, LogLevel::Error);
+        assert_eq!(entries[0].ts, 5);
+        assert_eq!(entries[0].target.as_deref(), Some("app::db"));
+    }
+
+    #[tokio::test]
+    async fn unsuccessful_queries_are_errors() {
+        let (url, _) = serve_once(500, "unavailable").await;
+        let error = FreeLogClient::new(url).query(LogQuery::new()).await;
+        assert!(matches!(error, Err(QueryError::Unsuccessful(x)) if x == "unavailable"));
+
+        let (url, _) = serve_once(200, r#"{"success":false,"entries":[]}"#).await;
+        let error = FreeLogClient::new(url).query(LogQuery::new()).await;
+        assert!(matches!(error, Err(QueryError::Unsuccessful(_))));
+    }
+}
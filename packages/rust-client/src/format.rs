@@ -0,0 +1,701 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+};
+
+use free_log_models::{iso8601_from_millis, LogComponent, LogEntryRequest};
+use serde_json::{Map, Value};
+use strum_macros::{AsRefStr, EnumString};
+
+use crate::{level_int, Level};
+
+const ECS_VERSION: &str = "8.11.0";
+
+#[derive(Debug, Default, Clone, Copy, EnumString, AsRefStr)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum EntryFormat {
+    #[default]
+    Json,
+    /// An Elastic Common Schema document. Scalar properties become `labels`, while objects and
+    /// arrays, which `labels` can't hold, go under the custom `free_log.properties` field set.
+    Ecs,
+    /// A human-readable line: timestamp, level, target, message, then sorted `key=value` pairs
+    Text,
+}
+
+/// How entries are delimited in file output
+#[derive(Debug, Default, Clone, Copy, EnumString, AsRefStr)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum Framing {
+    /// Each entry followed by `\n`
+    #[default]
+    Newline,
+    /// RFC 7464 JSON text sequences: each entry preceded by `\x1e` and followed by `\n`
+    RecordSeparator,
+    /// Each entry preceded by its length in bytes as a big-endian `u32`
+    LengthPrefixed,
+}
+
+impl Framing {
+    pub fn frame(self, body: &str) -> Vec<u8> {
+        let mut framed = Vec::with_capacity(body.len() + 5);
+
+        match self {
+            Self::Newline => {
+                framed.extend_from_slice(body.as_bytes());
+                framed.push(b'\n');
+            }
+            Self::RecordSeparator => {
+                framed.push(0x1e);
+                framed.extend_from_slice(body.as_bytes());
+                framed.push(b'\n');
+            }
+            Self::LengthPrefixed => {
+                framed.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                framed.extend_from_slice(body.as_bytes());
+            }
+        }
+
+        framed
+    }
+}
+
+/// How the keys of nested properties are written
+#[derive(Debug, Default, Clone, Copy, EnumString, AsRefStr)]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum PropertyKeys {
+    #[default]
+    Unchanged,
+    /// `{"user": {"id": 1}}` is written as `{"user.id": 1}`, and array items get their index as
+    /// a suffix, e.g. `tags.0`
+    Flat,
+    /// `{"user.id": 1}` is written as `{"user": {"id": 1}}`, and objects whose keys are exactly
+    /// `0..n` become arrays
+    Nested,
+}
+
+impl PropertyKeys {
+    /// The entry with its properties keyed this way, borrowed if unchanged
+    pub fn apply(self, entry: &LogEntryRequest) -> Cow<'_, LogEntryRequest> {
+        let properties = match (self, &entry.properties) {
+            (Self::Flat, Some(properties)) => flatten_properties(properties),
+            (Self::Nested, Some(properties)) => nest_properties(properties),
+            _ => return Cow::Borrowed(entry),
+        };
+
+        Cow::Owned(LogEntryRequest {
+            properties: Some(properties),
+            ..entry.clone()
+        })
+    }
+}
+
+pub fn flatten_properties(
+    properties: &HashMap<String, LogComponent>,
+) -> HashMap<String, LogComponent> {
+    let mut flattened = HashMap::new();
+
+    for (name, value) in properties {
+        flatten_into(&mut flattened, name.clone(), value);
+    }
+
+    flattened
+}
+
+fn flatten_into(out: &mut HashMap<String, LogComponent>, key: String, value: &LogComponent) {
+    match value {
+        LogComponent::Object(object) if !object.is_empty() => {
+            for (name, value) in object {
+                flatten_into(out, format!("{key}.{name}"), value);
+            }
+        }
+        LogComponent::Array(values) if !values.is_empty() => {
+            for (i, value) in values.iter().enumerate() {
+                flatten_into(out, format!("{key}.{i}"), value);
+            }
+        }
+        value => {
+            out.insert(key, value.clone());
+        }
+    }
+}
+
+/// The inverse of [`flatten_properties`]. A dotted key that collides with a non-object value,
+/// e.g. `a.b` next to `a`, is kept as-is.
+pub fn nest_properties(
+    properties: &HashMap<String, LogComponent>,
+) -> HashMap<String, LogComponent> {
+    let mut root = BTreeMap::new();
+    let mut collisions = vec![];
+
+    // Sorted so that collisions are resolved the same way every time
+    let sorted = properties.iter().collect::<BTreeMap<_, _>>();
+
+    for (name, value) in sorted {
+        if !insert_nested(&mut root, &name.split('.').collect::<Vec<_>>(), value) {
+            collisions.push((name.clone(), value.clone()));
+        }
+    }
+
+    root.into_iter()
+        .map(|(name, value)| (name, arrays_from_indices(value)))
+        .chain(collisions)
+        .collect()
+}
+
+fn insert_nested(
+    object: &mut BTreeMap<String, LogComponent>,
+    path: &[&str],
+    value: &LogComponent,
+) -> bool {
+    match path {
+        [] => false,
+        [name] if object.contains_key(*name) => false,
+        [name] => {
+            object.insert(name.to_string(), value.clone());
+            true
+        }
+        [name, rest @ ..] => match object
+            .entry(name.to_string())
+            .or_insert_with(|| LogComponent::Object(BTreeMap::new()))
+        {
+            LogComponent::Object(child) => insert_nested(child, rest, value),
+            _ => false,
+        },
+    }
+}
+
+fn arrays_from_indices(value: LogComponent) -> LogComponent {
+    let LogComponent::Object(object) = value else {
+        return value;
+    };
+
+    let is_array = !object.is_empty()
+        && (0..object.len()).all(|i| object.contains_key(i.to_string().as_str()));
+
+    if is_array {
+        let mut values = object
+            .into_iter()
+            .map(|(name, value)| (name.parse::<usize>().unwrap(), arrays_from_indices(value)))
+            .collect::<Vec<_>>();
+        values.sort_by_key(|(i, _)| *i);
+        LogComponent::Array(values.into_iter().map(|(_, value)| value).collect())
+    } else {
+        LogComponent::Object(
+            object
+                .into_iter()
+                .map(|(name, value)| (name, arrays_from_indices(value)))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LevelMapping {
+    levels: HashMap<Level, Value>,
+    numeric: bool,
+}
+
+impl LevelMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn map(mut self, level: impl Into<Level>, value: impl Into<Value>) -> Self {
+        self.levels.insert(level.into(), value.into());
+        self
+    }
+
+    /// Also emits `level_num`, from 0 for trace to 4 for error, next to `level` in the JSON and
+    /// ECS formats
+    pub fn numeric(mut self, value: bool) -> Self {
+        self.numeric = value;
+        self
+    }
+
+    pub fn get(&self, level: impl Into<Level>) -> Option<&Value> {
+        self.levels.get(&level.into())
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        self.numeric
+    }
+
+    /// Whether no level is mapped to another value. `level_num` may still be emitted, see
+    /// [`is_identity`](Self::is_identity).
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// Whether entries are serialized unchanged: no level is mapped and `level_num` isn't
+    /// emitted
+    pub fn is_identity(&self) -> bool {
+        self.is_empty() && !self.numeric
+    }
+}
+
+pub fn to_value(
+    entry: &LogEntryRequest,
+    format: EntryFormat,
+    levels: &LevelMapping,
+) -> Result<Value, serde_json::Error> {
+    match format {
+        EntryFormat::Json => {
+            let mut value = serde_json::to_value(entry)?;
+            if let Value::Object(object) = &mut value {
+                if let Some(level) = levels.get(entry.level) {
+                    object.insert("level".to_string(), level.clone());
+                }
+                if levels.is_numeric() {
+                    object.insert(
+                        "level_num".to_string(),
+                        level_int(entry.level.into()).into(),
+                    );
+                }
+            }
+            Ok(value)
+        }
+        EntryFormat::Ecs => Ok(to_ecs(entry, levels)),
+        EntryFormat::Text => Ok(to_text(entry, levels, None).into()),
+    }
+}
+
+/// `float_precision` only applies to the text format, so JSON output keeps full precision.
+pub fn to_string(
+    entry: &LogEntryRequest,
+    format: EntryFormat,
+    levels: &LevelMapping,
+    float_precision: Option<usize>,
+) -> Result<String, serde_json::Error> {
+    match format {
+        // The same encoding as `free_log_models::to_wire_bytes`
+        EntryFormat::Json if levels.is_identity() => serde_json::to_string(entry),
+        EntryFormat::Text => Ok(to_text(entry, levels, float_precision)),
+        format => serde_json::to_string(&to_value(entry, format, levels)?),
+    }
+}
+
+/// The largest integer a JavaScript number (an IEEE 754 double) can represent exactly
+pub const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Replaces integers outside `-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER` with their decimal strings
+/// so that JavaScript consumers don't lose precision
+pub fn stringify_large_integers(value: Value) -> Value {
+    match value {
+        Value::Number(number)
+            if number.as_u64().is_some_and(|x| x > MAX_SAFE_INTEGER)
+                || number
+                    .as_i64()
+                    .is_some_and(|x| x.unsigned_abs() > MAX_SAFE_INTEGER) =>
+        {
+            Value::String(number.to_string())
+        }
+        Value::Array(values) => values.into_iter().map(stringify_large_integers).collect(),
+        Value::Object(object) => object
+            .into_iter()
+            .map(|(name, value)| (name, stringify_large_integers(value)))
+            .collect::<Map<_, _>>()
+            .into(),
+        value => value,
+    }
+}
+
+pub fn rename_fields(value: Value, names: &HashMap<String, String>) -> Value {
+    match value {
+        Value::Object(object) if !names.is_empty() => object
+            .into_iter()
+            .map(|(name, value)| (names.get(&name).cloned().unwrap_or(name), value))
+            .collect::<Map<_, _>>()
+            .into(),
+        value => value,
+    }
+}
+
+/// Renders an entry's values as one space-separated string. Top-level strings are written as-is,
+/// while strings nested in arrays and objects are quoted, e.g. `took 42 {"path": "/a"}`.
+pub fn render_values(values: &[LogComponent], float_precision: Option<usize>) -> String {
+    let mut rendered = String::new();
+
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            rendered.push(' ');
+        }
+        render_component(&mut rendered, value, float_precision, false);
+    }
+
+    rendered
+}
+
+fn render_component(
+    out: &mut String,
+    value: &LogComponent,
+    float_precision: Option<usize>,
+    nested: bool,
+) {
+    match value {
+        LogComponent::Real(value) => match float_precision {
+            Some(precision) => out.push_str(&format!("{value:.precision$}")),
+            None => out.push_str(&value.to_string()),
+        },
+        LogComponent::Timestamp(_) if nested => {
+            out.push_str(&Value::from(value.to_string()).to_string())
+        }
+        LogComponent::String(value) if nested => {
+            out.push_str(&Value::from(value.as_str()).to_string())
+        }
+        LogComponent::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                render_component(out, value, float_precision, true);
+            }
+            out.push(']');
+        }
+        LogComponent::Object(object) => {
+            out.push('{');
+            for (i, (name, value)) in object.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                out.push_str(&Value::from(name.as_str()).to_string());
+                out.push_str(": ");
+                render_component(out, value, float_precision, true);
+            }
+            out.push('}');
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+fn to_text(
+    entry: &LogEntryRequest,
+    levels: &LevelMapping,
+    float_precision: Option<usize>,
+) -> String {
+    let level = match levels.get(entry.level) {
+        Some(Value::String(level)) => level.clone(),
+        Some(level) => level.to_string(),
+        None => entry.level.as_ref().to_string(),
+    };
+
+    let mut line = format!("{} {level}", iso8601_from_millis(entry.ts as i64));
+
+    if let Some(target) = entry.target.as_ref().or(entry.module_path.as_ref()) {
+        line.push(' ');
+        line.push_str(target);
+        line.push(':');
+    }
+
+    if !entry.values.is_empty() {
+        line.push(' ');
+        line.push_str(&render_values(&entry.values, float_precision));
+    }
+
+    let pairs = entry
+        .properties
+        .iter()
+        .chain(entry.fields.iter())
+        .flatten()
+        .collect::<std::collections::BTreeMap<_, _>>();
+
+    for (name, value) in pairs {
+        line.push_str(&format!(" {name}="));
+        render_component(&mut line, value, float_precision, false);
+    }
+
+    line
+}
+
+fn to_ecs(entry: &LogEntryRequest, levels: &LevelMapping) -> Value {
+    let message = render_values(&entry.values, None);
+
+    let mut log = Map::new();
+    log.insert(
+        "level".to_string(),
+        levels
+            .get(entry.level)
+            .cloned()
+            .unwrap_or_else(|| entry.level.as_ref().to_lowercase().into()),
+    );
+    if levels.is_numeric() {
+        log.insert(
+            "level_num".to_string(),
+            level_int(entry.level.into()).into(),
+        );
+    }
+    if let Some(target) = &entry.target {
+        log.insert("logger".to_string(), target.clone().into());
+    }
+    if let Some(location) = &entry.location {
+        let file = match location.rsplit_once(':') {
+            Some((name, line)) if line.parse::<u64>().is_ok() => serde_json::json!({
+                "name": name,
+                "line": line.parse::<u64>().unwrap(),
+            }),
+            _ => serde_json::json!({ "name": location }),
+        };
+        log.insert("origin".to_string(), serde_json::json!({ "file": file }));
+    }
+
+    let mut document = Map::new();
+    document.insert(
+        "@timestamp".to_string(),
+        iso8601_from_millis(entry.ts as i64).into(),
+    );
+    document.insert("message".to_string(), message.into());
+    document.insert("log".to_string(), log.into());
+    document.insert(
+        "ecs".to_string(),
+        serde_json::json!({ "version": ECS_VERSION }),
+    );
+
+    if let Some(id) = &entry.id {
+        document.insert("event".to_string(), serde_json::json!({ "id": id }));
+    }
+
+    if let Some(properties) = &entry.properties {
+        let (nested, labels): (Map<_, _>, Map<_, _>) = properties
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.clone(),
+                    serde_json::to_value(value).unwrap_or(Value::Null),
+                )
+            })
+            .partition(|(_, value)| value.is_object() || value.is_array());
+
+        if !labels.is_empty() {
+            document.insert("labels".to_string(), labels.into());
+        }
+        if !nested.is_empty() {
+            document.insert(
+                "free_log".to_string(),
+                serde_json::json!({ "properties": nested }),
+            );
+        }
+    }
+
+    document.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use free_log_models::LogLevel;
+
+    use super::*;
+
+    fn entry(values: Vec<LogComponent>) -> LogEntryRequest {
+        LogEntryRequest {
+            level: LogLevel::Info,
+            ts: 0,
+            values,
+            target: None,
+            module_path: None,
+            location: None,
+            properties: Some(HashMap::from([(
+                "ratio".to_string(),
+                LogComponent::Real(0.333333333),
+            )])),
+            fields: None,
+            id: None,
+            priority: None,
+            audit: false,
+        }
+    }
+
+    #[test]
+    fn float_precision_only_applies_to_text() {
+        let entry = entry(vec![
+            LogComponent::String("took".to_string()),
+            LogComponent::Real(1.23456789),
+        ]);
+        let levels = LevelMapping::new();
+
+        let text = to_string(&entry, EntryFormat::Text, &levels, Some(3)).unwrap();
+        let json = to_string(&entry, EntryFormat::Json, &levels, Some(3)).unwrap();
+
+        assert!(text.ends_with("took 1.235 ratio=0.333"), "{text}");
+        assert!(
+            json.contains("1.23456789") && json.contains("0.333333333"),
+            "{json}"
+        );
+    }
+
+    #[test]
+    fn json_without_level_names_is_the_wire_encoding() {
+        let entry = entry(vec![LogComponent::String("hello \u{1F600}".to_string())]);
+
+        let json = to_string(&entry, EntryFormat::Json, &LevelMapping::new(), None).unwrap();
+        let read = free_log_models::from_wire_bytes(json.as_bytes()).unwrap();
+
+        assert_eq!(
+            json.as_bytes(),
+            free_log_models::to_wire_bytes(&entry).unwrap()
+        );
+        assert_eq!(
+            to_string(&read, EntryFormat::Json, &LevelMapping::new(), None).unwrap(),
+            json
+        );
+    }
+
+    #[test]
+    fn a_numeric_level_mapping_is_not_the_identity() {
+        let numeric = LevelMapping::new().numeric(true);
+
+        assert!(LevelMapping::new().is_identity());
+        assert!(numeric.is_empty() && !numeric.is_identity());
+        assert!(!LevelMapping::new().map(Level::Warn, "warning").is_empty());
+
+        let json = to_string(&entry(vec![]), EntryFormat::Json, &numeric, None).unwrap();
+        let value = serde_json::from_str::<Value>(&json).unwrap();
+
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["level_num"], 2);
+    }
+
+    #[test]
+    fn ecs_keeps_only_scalar_properties_in_labels() {
+        let mut entry = entry(vec![LogComponent::String("paid".to_string())]);
+        entry.level = LogLevel::Warn;
+        entry.ts = 1_700_000_000_123;
+        entry.target = Some("billing".to_string());
+        entry.location = Some("src/billing.rs:42".to_string());
+        entry.id = Some("01HX".to_string());
+        entry.properties = Some(HashMap::from([
+            ("user".to_string(), LogComponent::String("bob".to_string())),
+            ("attempt".to_string(), LogComponent::UInteger(2)),
+            (
+                "items".to_string(),
+                LogComponent::Array(vec![LogComponent::String("book".to_string())]),
+            ),
+            (
+                "card".to_string(),
+                LogComponent::Object(BTreeMap::from([(
+                    "brand".to_string(),
+                    LogComponent::String("visa".to_string()),
+                )])),
+            ),
+        ]));
+
+        assert_eq!(
+            to_ecs(&entry, &LevelMapping::new()),
+            serde_json::json!({
+                "@timestamp": "2023-11-14T22:13:20.123Z",
+                "message": "paid",
+                "log": {
+                    "level": "warn",
+                    "logger": "billing",
+                    "origin": {"file": {"name": "src/billing.rs", "line": 42}},
+                },
+                "ecs": {"version": ECS_VERSION},
+                "event": {"id": "01HX"},
+                "labels": {"user": "bob", "attempt": 2},
+                "free_log": {
+                    "properties": {
+                        "items": ["book"],
+                        "card": {"brand": "visa"},
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn text_renders_every_value_of_a_multi_component_entry() {
+        let mut entry = entry(vec![
+            LogComponent::String("took".to_string()),
+            LogComponent::UInteger(42),
+            LogComponent::Object(BTreeMap::from([
+                ("path".to_string(), LogComponent::String("/a".to_string())),
+                (
+                    "tags".to_string(),
+                    LogComponent::Array(vec![
+                        LogComponent::String("x".to_string()),
+                        LogComponent::Boolean(true),
+                    ]),
+                ),
+            ])),
+        ]);
+        entry.properties = None;
+
+        let text = to_string(&entry, EntryFormat::Text, &LevelMapping::new(), None).unwrap();
+
+        assert_eq!(
+            text,
+            r#"1970-01-01T00:00:00.000Z INFO took 42 {"path": "/a", "tags": ["x", true]}"#
+        );
+    }
+
+    #[test]
+    fn each_framing_mode_delimits_entries_with_its_own_bytes() {
+        assert_eq!(Framing::Newline.frame("{}"), b"{}\n");
+        assert_eq!(Framing::RecordSeparator.frame("{}"), b"\x1e{}\n");
+        assert_eq!(Framing::LengthPrefixed.frame("{}"), b"\0\0\0\x02{}");
+        assert_eq!(
+            Framing::LengthPrefixed.frame("\u{e9}"),
+            [0, 0, 0, 2, 0xc3, 0xa9]
+        );
+    }
+
+    #[test]
+    fn only_integers_beyond_the_safe_range_become_strings() {
+        let value = serde_json::json!({
+            "big": MAX_SAFE_INTEGER + 1,
+            "safe": MAX_SAFE_INTEGER,
+            "negative": -(MAX_SAFE_INTEGER as i64) - 1,
+            "nested": [u64::MAX, 1.5],
+        });
+
+        assert_eq!(
+            stringify_large_integers(value),
+            serde_json::json!({
+                "big": "9007199254740992",
+                "safe": MAX_SAFE_INTEGER,
+                "negative": "-9007199254740992",
+                "nested": ["18446744073709551615", 1.5],
+            })
+        );
+    }
+
+    #[test]
+    fn property_keys_flatten_and_nest_the_same_properties() {
+        let mut nested = entry(vec![]);
+        nested.properties = Some(HashMap::from([
+            (
+                "user".to_string(),
+                LogComponent::Object(BTreeMap::from([
+                    ("id".to_string(), LogComponent::UInteger(1)),
+                    (
+                        "tags".to_string(),
+                        LogComponent::Array(vec![
+                            LogComponent::String("a".to_string()),
+                            LogComponent::String("b".to_string()),
+                        ]),
+                    ),
+                ])),
+            ),
+            ("ratio".to_string(), LogComponent::Real(0.5)),
+        ]));
+        let properties =
+            |entry: &LogEntryRequest| serde_json::to_value(entry).unwrap()["properties"].clone();
+
+        let flat = PropertyKeys::Flat.apply(&nested);
+        assert_eq!(
+            properties(&flat),
+            serde_json::json!({"ratio": 0.5, "user.id": 1, "user.tags.0": "a", "user.tags.1": "b"})
+        );
+
+        let renested = PropertyKeys::Nested.apply(&flat);
+        assert_eq!(
+            properties(&renested),
+            serde_json::json!({"ratio": 0.5, "user": {"id": 1, "tags": ["a", "b"]}})
+        );
+        assert_eq!(properties(&renested), properties(&nested));
+        assert!(matches!(
+            PropertyKeys::Unchanged.apply(&nested),
+            Cow::Borrowed(_)
+        ));
+    }
+}
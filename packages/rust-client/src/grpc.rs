@@ -0,0 +1,270 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use free_log_models::{LogComponent, LogEntryRequest};
+use tokio::sync::OnceCell;
+use tonic::transport::{Channel, Endpoint};
+
+use crate::{FlushError, LogWriter, WriterKind};
+
+/// Types and the `LogIngest` client and server generated from `proto/free_log.proto`
+pub mod proto {
+    tonic::include_proto!("free_log.v1");
+}
+
+impl From<&LogComponent> for proto::LogValue {
+    fn from(value: &LogComponent) -> Self {
+        use proto::log_value::Kind;
+
+        let kind = match value {
+            LogComponent::Integer(value) => Kind::Integer(*value as i64),
+            LogComponent::UInteger(value) => Kind::Uinteger(*value as u64),
+            LogComponent::Real(value) => Kind::Real(*value),
+            LogComponent::String(value) => Kind::String(value.clone()),
+            LogComponent::Boolean(value) => Kind::Boolean(*value),
+            LogComponent::Timestamp(value) => Kind::Timestamp(*value),
+            LogComponent::Array(values) => Kind::Array(proto::LogValueList {
+                values: values.iter().map(Into::into).collect(),
+            }),
+            LogComponent::Object(object) => Kind::Object(proto::LogValueMap {
+                values: to_proto_map(object),
+            }),
+            LogComponent::Undefined | LogComponent::Null => Kind::Null(true),
+        };
+
+        Self { kind: Some(kind) }
+    }
+}
+
+impl From<&LogEntryRequest> for proto::LogEntry {
+    fn from(entry: &LogEntryRequest) -> Self {
+        Self {
+            level: entry.level.as_ref().to_string(),
+            ts: entry.ts as u64,
+            values: entry.values.iter().map(Into::into).collect(),
+            target: entry.target.clone(),
+            module_path: entry.module_path.clone(),
+            location: entry.location.clone(),
+            properties: entry
+                .properties
+                .as_ref()
+                .map(to_proto_map)
+                .unwrap_or_default(),
+            fields: entry.fields.as_ref().map(to_proto_map).unwrap_or_default(),
+            id: entry.id.clone(),
+            priority: entry.priority.map(u32::from),
+            audit: entry.audit,
+        }
+    }
+}
+
+fn to_proto_map<'a>(
+    values: impl IntoIterator<Item = (&'a String, &'a LogComponent)>,
+) -> HashMap<String, proto::LogValue> {
+    values
+        .into_iter()
+        .map(|(name, value)| (name.clone(), value.into()))
+        .collect()
+}
+
+/// Streams entries as protobuf messages to the `LogIngest.Write` method of a gRPC endpoint,
+/// described by `proto/free_log.proto`. The connection is opened on the first write.
+#[derive(Debug, Clone)]
+pub struct GrpcWriter {
+    endpoint: Endpoint,
+    channel: OnceCell<Channel>,
+}
+
+impl GrpcWriter {
+    pub fn new(url: impl Into<String>) -> Result<Self, tonic::transport::Error> {
+        Ok(Self {
+            endpoint: Endpoint::from_shared(url.into())?,
+            channel: OnceCell::new(),
+        })
+    }
+
+    pub fn timeout(mut self, value: impl Into<Duration>) -> Self {
+        self.endpoint = self.endpoint.timeout(value.into());
+        self
+    }
+
+    pub fn connect_timeout(mut self, value: impl Into<Duration>) -> Self {
+        self.endpoint = self.endpoint.connect_timeout(value.into());
+        self
+    }
+}
+
+#[async_trait]
+impl LogWriter for GrpcWriter {
+    async fn write(&self, entries: &[LogEntryRequest]) -> Result<(), FlushError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let channel = self
+            .channel
+            .get_or_try_init(|| self.endpoint.connect())
+            .await?
+            .clone();
+
+        let messages = entries
+            .iter()
+            .map(proto::LogEntry::from)
+            .collect::<Vec<_>>();
+
+        proto::log_ingest_client::LogIngestClient::new(channel)
+            .write(tokio_stream::iter(messages))
+            .await?;
+
+        Ok(())
+    }
+
+    fn kind(&self) -> WriterKind {
+        WriterKind::Grpc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use free_log_models::LogLevel;
+    use parking_lot::Mutex;
+    use proto::log_ingest_server::{LogIngest, LogIngestServer};
+    use tonic::{Request, Response, Status, Streaming};
+
+    use super::*;
+
+    /// Records the entries it receives, or rejects every stream while `failing` is set
+    #[derive(Debug, Default)]
+    struct Recorder {
+        entries: Mutex<Vec<proto::LogEntry>>,
+        failing: bool,
+    }
+
+    #[tonic::async_trait]
+    impl LogIngest for Arc<Recorder> {
+        async fn write(
+            &self,
+            request: Request<Streaming<proto::LogEntry>>,
+        ) -> Result<Response<proto::WriteResponse>, Status> {
+            if self.failing {
+                return Err(Status::unavailable("down for maintenance"));
+            }
+
+            let mut stream = request.into_inner();
+            let mut accepted = 0;
+
+            while let Some(entry) = stream.message().await? {
+                self.entries.lock().push(entry);
+                accepted += 1;
+            }
+
+            Ok(Response::new(proto::WriteResponse { accepted }))
+        }
+    }
+
+    async fn serve(recorder: Arc<Recorder>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(LogIngestServer::new(recorder))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        url
+    }
+
+    fn entry(values: Vec<LogComponent>) -> LogEntryRequest {
+        LogEntryRequest {
+            level: LogLevel::Warn,
+            ts: 1_700_000_000_000,
+            values,
+            target: Some("app::db".to_string()),
+            module_path: None,
+            location: None,
+            properties: Some(HashMap::from([(
+                "tenant".to_string(),
+                LogComponent::String("a".to_string()),
+            )])),
+            fields: None,
+            id: Some("01HF0000000000000000000000".to_string()),
+            priority: Some(3),
+            audit: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_are_streamed_to_the_server() {
+        let recorder = Arc::new(Recorder::default());
+        let writer = GrpcWriter::new(serve(recorder.clone()).await).unwrap();
+
+        writer
+            .write(&[
+                entry(vec![
+                    LogComponent::String("slow query".to_string()),
+                    LogComponent::Real(1.5),
+                    LogComponent::Object(BTreeMap::from([(
+                        "rows".to_string(),
+                        LogComponent::UInteger(3),
+                    )])),
+                ]),
+                entry(vec![LogComponent::Null]),
+            ])
+            .await
+            .unwrap();
+
+        let entries = recorder.entries.lock();
+        assert_eq!(entries.len(), 2);
+
+        let first = &entries[0];
+        assert_eq!(first.level, "WARN");
+        assert_eq!(first.ts, 1_700_000_000_000);
+        assert_eq!(first.target.as_deref(), Some("app::db"));
+        assert_eq!(first.priority, Some(3));
+        assert!(first.audit);
+        assert_eq!(
+            first.values[0],
+            (&LogComponent::String("slow query".into())).into()
+        );
+        assert_eq!(first.values[1], (&LogComponent::Real(1.5)).into());
+        assert_eq!(
+            first.properties["tenant"],
+            (&LogComponent::String("a".into())).into()
+        );
+
+        let Some(proto::log_value::Kind::Object(object)) = &first.values[2].kind else {
+            panic!("Expected an object, got {:?}", first.values[2]);
+        };
+        assert_eq!(
+            object.values["rows"].kind,
+            Some(proto::log_value::Kind::Uinteger(3))
+        );
+
+        assert_eq!(
+            entries[1].values[0].kind,
+            Some(proto::log_value::Kind::Null(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_rejected_stream_fails_the_write() {
+        let recorder = Arc::new(Recorder {
+            failing: true,
+            ..Default::default()
+        });
+        let writer = GrpcWriter::new(serve(recorder).await).unwrap();
+
+        let err = writer
+            .write(&[entry(vec![LogComponent::String("lost".to_string())])])
+            .await
+            .unwrap_err();
+
+        let FlushError::GrpcStatus(status) = err else {
+            panic!("Expected a gRPC status, got {err:?}");
+        };
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+}
@@ -0,0 +1,260 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use async_trait::async_trait;
+use free_log_models::LogEntryRequest;
+use serde_json::Value;
+
+use crate::{
+    format::{self, EntryFormat, LevelMapping},
+    FlushError, LogWriter, WriterKind,
+};
+
+const PUSH_PATH: &str = "/loki/api/v1/push";
+
+/// Pushes entries to Grafana Loki. Entries are grouped into streams by their labels, which come
+/// from static values, the entry's target and chosen properties.
+#[derive(Debug, Clone)]
+pub struct LokiWriterConfig {
+    url: String,
+    static_labels: BTreeMap<String, String>,
+    property_labels: Vec<(String, String)>,
+    target_label: Option<String>,
+    level_label: Option<String>,
+    format: EntryFormat,
+    timeout: Option<Duration>,
+    client: reqwest::Client,
+}
+
+impl LokiWriterConfig {
+    /// `url` is the base URL of the Loki server, e.g. `http://localhost:3100`
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            static_labels: BTreeMap::new(),
+            property_labels: vec![],
+            target_label: None,
+            level_label: None,
+            format: EntryFormat::default(),
+            timeout: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Adds a label with the same value on every stream, e.g. `app` or `env`
+    pub fn static_label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.static_labels.insert(name.into(), value.into());
+        self
+    }
+
+    /// Labels each entry with the value of one of its properties. Entries without the property
+    /// don't get the label.
+    pub fn property_label(mut self, property: impl Into<String>, label: impl Into<String>) -> Self {
+        self.property_labels.push((property.into(), label.into()));
+        self
+    }
+
+    /// Labels each entry with its target, falling back to its module path
+    pub fn target_label(mut self, label: impl Into<String>) -> Self {
+        self.target_label = Some(label.into());
+        self
+    }
+
+    pub fn level_label(mut self, label: impl Into<String>) -> Self {
+        self.level_label = Some(label.into());
+        self
+    }
+
+    /// How each entry's log line is formatted. Defaults to JSON.
+    pub fn format(mut self, value: impl Into<EntryFormat>) -> Self {
+        self.format = value.into();
+        self
+    }
+
+    pub fn timeout(mut self, value: impl Into<Duration>) -> Self {
+        self.timeout = Some(value.into());
+        self
+    }
+
+    fn labels(&self, entry: &LogEntryRequest) -> BTreeMap<String, String> {
+        let mut labels = self.static_labels.clone();
+
+        if let Some(label) = &self.target_label {
+            if let Some(target) = entry.target.as_ref().or(entry.module_path.as_ref()) {
+                labels.insert(label.clone(), target.clone());
+            }
+        }
+
+        if let Some(label) = &self.level_label {
+            labels.insert(label.clone(), entry.level.as_ref().to_lowercase());
+        }
+
+        if let Some(properties) = &entry.properties {
+            for (property, label) in &self.property_labels {
+                if let Some(value) = properties.get(property) {
+                    labels.insert(label.clone(), value.to_string());
+                }
+            }
+        }
+
+        labels
+    }
+
+    /// Builds the push payload, `{"streams": [{"stream": {..labels}, "values": [[ts, line]]}]}`,
+    /// with timestamps in nanoseconds since the epoch as strings. Entries that fail to format are
+    /// skipped rather than failing the whole push.
+    pub fn payload(&self, entries: &[LogEntryRequest]) -> Value {
+        let mut streams = BTreeMap::<_, Vec<Value>>::new();
+
+        for entry in entries {
+            let line = match format::to_string(entry, self.format, &LevelMapping::new(), None) {
+                Ok(line) => line,
+                Err(err) => {
+                    eprintln!("Skipping entry that failed to format for Loki: {err:?}");
+                    continue;
+                }
+            };
+            let ts = (entry.ts as u128 * 1_000_000).to_string();
+
+            streams
+                .entry(self.labels(entry))
+                .or_default()
+                .push(serde_json::json!([ts, line]));
+        }
+
+        let streams = streams
+            .into_iter()
+            .map(|(stream, values)| serde_json::json!({ "stream": stream, "values": values }))
+            .collect::<Vec<_>>();
+
+        serde_json::json!({ "streams": streams })
+    }
+}
+
+#[async_trait]
+impl LogWriter for LokiWriterConfig {
+    async fn write(&self, entries: &[LogEntryRequest]) -> Result<(), FlushError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut request = self
+            .client
+            .post(format!("{}{PUSH_PATH}", self.url.trim_end_matches('/')))
+            .json(&self.payload(entries));
+
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+
+        let response = request.send().await?;
+
+        // Loki answers a successful push with 204 No Content
+        if !response.status().is_success() {
+            return Err(FlushError::Unsuccessful(
+                response
+                    .text()
+                    .await
+                    .unwrap_or("(failed to get response text)".to_string()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn kind(&self) -> WriterKind {
+        WriterKind::Loki
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use free_log_models::{LogComponent, LogLevel};
+
+    use super::*;
+
+    fn entry(
+        level: LogLevel,
+        ts: usize,
+        message: &str,
+        target: Option<&str>,
+        region: Option<&str>,
+    ) -> LogEntryRequest {
+        LogEntryRequest {
+            level,
+            ts,
+            values: vec![LogComponent::String(message.to_string())],
+            target: target.map(ToString::to_string),
+            module_path: Some("worker".to_string()),
+            location: None,
+            properties: region.map(|x| {
+                HashMap::from([("region".to_string(), LogComponent::String(x.to_string()))])
+            }),
+            fields: None,
+            id: None,
+            priority: None,
+            audit: false,
+        }
+    }
+
+    #[test]
+    fn entries_are_grouped_into_streams_by_their_labels() {
+        let config = LokiWriterConfig::new("http://loki")
+            .static_label("app", "shop")
+            .target_label("target")
+            .level_label("level")
+            .property_label("region", "region")
+            .format(EntryFormat::Text);
+
+        let payload = config.payload(&[
+            entry(
+                LogLevel::Info,
+                1_700_000_000_123,
+                "a",
+                Some("api"),
+                Some("eu"),
+            ),
+            entry(LogLevel::Error, 5, "b", None, None),
+            entry(
+                LogLevel::Info,
+                1_700_000_000_124,
+                "c",
+                Some("api"),
+                Some("eu"),
+            ),
+        ]);
+
+        let streams = payload["streams"].as_array().unwrap();
+        let lines = |stream: &Value| {
+            stream["values"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| (x[0].clone(), x[1].as_str().unwrap().to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(streams.len(), 2);
+        assert_eq!(
+            streams[0]["stream"],
+            serde_json::json!({"app": "shop", "level": "error", "target": "worker"})
+        );
+        assert_eq!(
+            streams[1]["stream"],
+            serde_json::json!({"app": "shop", "level": "info", "region": "eu", "target": "api"})
+        );
+
+        let errors = lines(&streams[0]);
+        let infos = lines(&streams[1]);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "5000000");
+        assert!(errors[0].1.ends_with(" b"), "{}", errors[0].1);
+        assert_eq!(
+            infos.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+            ["1700000000123000000", "1700000000124000000"]
+        );
+        assert!(infos[0].1.contains(" a") && infos[1].1.contains(" c"));
+    }
+}
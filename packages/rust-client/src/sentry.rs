@@ -0,0 +1,188 @@
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use free_log_models::{LogEntryRequest, LogLevel};
+use sentry_core::protocol::{Breadcrumb, Event, Map, Value};
+
+use crate::{level_int, FlushError, Level, LogWriter, WriterKind};
+
+/// Forwards entries to the Sentry hub bound to the current thread. Entries at or above the event
+/// level are captured as events, and lower levels are recorded as breadcrumbs.
+#[derive(Debug, Clone)]
+pub struct SentryWriter {
+    event_level: Level,
+}
+
+impl Default for SentryWriter {
+    fn default() -> Self {
+        Self {
+            event_level: Level::Error,
+        }
+    }
+}
+
+impl SentryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn event_level(mut self, value: impl Into<Level>) -> Self {
+        self.event_level = value.into();
+        self
+    }
+}
+
+#[async_trait]
+impl LogWriter for SentryWriter {
+    async fn write(&self, entries: &[LogEntryRequest]) -> Result<(), FlushError> {
+        for entry in entries {
+            if level_int(entry.level.into()) >= level_int(self.event_level) {
+                sentry_core::capture_event(to_event(entry));
+            } else {
+                sentry_core::add_breadcrumb(to_breadcrumb(entry));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn kind(&self) -> WriterKind {
+        WriterKind::Sentry
+    }
+}
+
+fn to_event(entry: &LogEntryRequest) -> Event<'static> {
+    Event {
+        level: to_sentry_level(entry.level),
+        message: Some(message(entry)),
+        logger: entry.target.clone().or_else(|| entry.module_path.clone()),
+        culprit: entry.location.clone(),
+        timestamp: timestamp(entry),
+        extra: data(entry),
+        ..Default::default()
+    }
+}
+
+fn to_breadcrumb(entry: &LogEntryRequest) -> Breadcrumb {
+    Breadcrumb {
+        ty: "log".to_string(),
+        level: to_sentry_level(entry.level),
+        category: entry.target.clone().or_else(|| entry.module_path.clone()),
+        message: Some(message(entry)),
+        timestamp: timestamp(entry),
+        data: data(entry),
+    }
+}
+
+fn to_sentry_level(level: LogLevel) -> sentry_core::Level {
+    match level {
+        LogLevel::Trace | LogLevel::Debug => sentry_core::Level::Debug,
+        LogLevel::Info => sentry_core::Level::Info,
+        LogLevel::Warn => sentry_core::Level::Warning,
+        LogLevel::Error => sentry_core::Level::Error,
+    }
+}
+
+fn message(entry: &LogEntryRequest) -> String {
+    entry
+        .values
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn timestamp(entry: &LogEntryRequest) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(entry.ts as u64)
+}
+
+fn data(entry: &LogEntryRequest) -> Map<String, Value> {
+    entry
+        .properties
+        .iter()
+        .chain(entry.fields.iter())
+        .flatten()
+        .map(|(name, value)| {
+            (
+                name.clone(),
+                serde_json::to_value(value).unwrap_or(Value::Null),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use free_log_models::LogComponent;
+
+    use super::*;
+
+    fn entry(level: LogLevel, message: &str) -> LogEntryRequest {
+        LogEntryRequest {
+            level,
+            ts: 1_700_000_000_000,
+            values: vec![LogComponent::String(message.to_string())],
+            target: Some("billing::charge".to_string()),
+            module_path: None,
+            location: None,
+            properties: None,
+            fields: None,
+            id: None,
+            priority: None,
+            audit: false,
+        }
+    }
+
+    fn write(writer: &SentryWriter, entries: &[LogEntryRequest]) -> Vec<Event<'static>> {
+        sentry_core::test::with_captured_events(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(writer.write(entries))
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn an_error_entry_is_captured_as_an_event_with_earlier_breadcrumbs() {
+        let mut error = entry(LogLevel::Error, "charge failed");
+        error.properties = Some(HashMap::from([(
+            "customer".to_string(),
+            LogComponent::String("c_42".to_string()),
+        )]));
+
+        let events = write(
+            &SentryWriter::new(),
+            &[entry(LogLevel::Info, "charging"), error],
+        );
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, sentry_core::Level::Error);
+        assert_eq!(event.message.as_deref(), Some("charge failed"));
+        assert_eq!(event.logger.as_deref(), Some("billing::charge"));
+        assert_eq!(event.extra["customer"], "c_42");
+        assert_eq!(
+            event.timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)
+        );
+
+        let breadcrumbs = &event.breadcrumbs.values;
+        assert_eq!(breadcrumbs.len(), 1);
+        assert_eq!(breadcrumbs[0].level, sentry_core::Level::Info);
+        assert_eq!(breadcrumbs[0].message.as_deref(), Some("charging"));
+    }
+
+    #[test]
+    fn warnings_become_events_when_the_event_level_is_lowered() {
+        let entries = [entry(LogLevel::Warn, "slow charge")];
+
+        assert!(write(&SentryWriter::new(), &entries).is_empty());
+
+        let events = write(&SentryWriter::new().event_level(Level::Warn), &entries);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, sentry_core::Level::Warning);
+    }
+}
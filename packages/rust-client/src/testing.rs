@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use free_log_models::LogEntryRequest;
+use parking_lot::{Mutex, MutexGuard};
+
+static CAPTURE_LOCK: Mutex<()> = Mutex::new(());
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static CAPTURED: Mutex<Vec<LogEntryRequest>> = Mutex::new(vec![]);
+
+/// Only one capture can be active at a time, so tests that capture run one after another.
+pub struct CaptureGuard {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        CAPTURING.store(false, Ordering::SeqCst);
+        CAPTURED.lock().clear();
+    }
+}
+
+pub fn capture() -> CaptureGuard {
+    let lock = CAPTURE_LOCK.lock();
+    CAPTURED.lock().clear();
+    CAPTURING.store(true, Ordering::SeqCst);
+
+    CaptureGuard { _lock: lock }
+}
+
+pub fn captured() -> Vec<LogEntryRequest> {
+    CAPTURED.lock().clone()
+}
+
+pub(crate) fn try_capture(entries: &[LogEntryRequest]) -> bool {
+    if !CAPTURING.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    CAPTURED.lock().extend(entries.iter().cloned());
+
+    true
+}
+
+/// Holds the capture lock without capturing, so that a test's flushes reach its writers even
+/// while other tests capture
+#[cfg(test)]
+pub(crate) fn exclusive() -> CaptureGuard {
+    CaptureGuard {
+        _lock: CAPTURE_LOCK.lock(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use super::*;
+    use crate::{FreeLogLayer, LogsConfig};
+
+    fn log(layer: &FreeLogLayer, message: &str) {
+        tracing::subscriber::with_default(
+            tracing_subscriber::registry().with(layer.clone()),
+            || {
+                tracing::info!("{message}");
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn flushes_are_captured_while_the_guard_is_held() {
+        let layer = FreeLogLayer::new(LogsConfig::builder().build().unwrap());
+        let _capture = capture();
+
+        log(&layer, "first");
+        layer.flush().await.unwrap();
+        log(&layer, "second");
+        layer.flush().await.unwrap();
+
+        let messages = captured()
+            .into_iter()
+            .map(|x| x.values[0].to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(messages, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn captured_entries_are_reset_between_captures() {
+        let layer = FreeLogLayer::new(LogsConfig::builder().build().unwrap());
+
+        {
+            let _capture = capture();
+            log(&layer, "captured");
+            layer.flush().await.unwrap();
+            assert_eq!(captured().len(), 1);
+        }
+
+        assert!(captured().is_empty());
+
+        let _capture = capture();
+        assert!(captured().is_empty());
+    }
+}
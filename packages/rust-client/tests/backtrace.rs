@@ -0,0 +1,40 @@
+//! Runs in its own process, as std reads `RUST_LIB_BACKTRACE` once and caches it
+
+use free_log_client::{FileWriterConfig, FreeLogLayer, LogsConfig};
+use tracing_subscriber::layer::SubscriberExt as _;
+
+#[tokio::test]
+async fn errors_carry_a_backtrace_when_backtraces_are_enabled() {
+    std::env::set_var("RUST_LIB_BACKTRACE", "1");
+
+    let path = std::env::temp_dir().join(format!("free_log_{}_backtrace.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let layer = FreeLogLayer::new(
+        LogsConfig::builder()
+            .auto_flush(false)
+            .with_file_writer(FileWriterConfig::builder().file_path(&path))
+            .unwrap()
+            .build()
+            .unwrap(),
+    );
+
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer.clone()), || {
+        tracing::error!(error = "connection reset", "request failed");
+        tracing::info!("request retried");
+    });
+    layer.flush().await.unwrap();
+
+    let written = std::fs::read_to_string(&path).unwrap();
+    let lines = written
+        .lines()
+        .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+        .collect::<Vec<_>>();
+    std::fs::remove_file(&path).unwrap();
+
+    let backtrace = lines[0]["properties"]["error.backtrace"].as_str().unwrap();
+    assert!(
+        backtrace.contains("errors_carry_a_backtrace"),
+        "{backtrace}"
+    );
+    assert!(lines[1]["properties"].get("error.backtrace").is_none());
+}
@@ -0,0 +1,34 @@
+//! Runs in its own process, as both the `log` logger and the tracing subscriber are global
+
+use std::sync::Mutex;
+
+use free_log_client::{init, LogsConfig};
+use tracing_log::log;
+
+static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct AppLogger;
+
+impl log::Log for AppLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        RECORDS.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn init_leaves_the_application_logger_in_place_when_the_bridge_is_disabled() {
+    log::set_logger(&AppLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    init(LogsConfig::builder().log_tracer(false).auto_flush(false)).unwrap();
+
+    log::info!("handled by the app");
+
+    assert_eq!(*RECORDS.lock().unwrap(), ["handled by the app"]);
+}
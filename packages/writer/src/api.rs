@@ -1,7 +1,10 @@
 use actix_web::{
-    web::{self, Json},
-    HttpRequest, Result,
+    http::header::ContentType,
+    web::{self, Bytes, Json},
+    HttpRequest, HttpResponse, Result,
 };
+use free_log_models::{to_wire_bytes, LogEntryRequest, LogQuery};
+use futures_util::StreamExt as _;
 use lambda_web::actix_web::{self, get, post};
 use serde::Deserialize;
 use serde_json::Value;
@@ -17,12 +20,44 @@ pub enum LogsError {
     NotFound { error: String },
 }
 
-#[derive(Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct GetLogsQuery {}
-
+/// Returns the entries this instance wrote most recently, see [`crate::recent_entries_capacity`]
 #[get("/logs")]
-pub async fn get_logs_endpoint(_query: web::Query<GetLogsQuery>) -> Result<Json<Value>> {
+pub async fn get_logs_endpoint(query: web::Query<LogQuery>) -> Result<HttpResponse> {
+    Ok(stream_logs_response(crate::recent_entries(&query)))
+}
+
+/// Serializes the entries one at a time as the body is consumed rather than building the whole
+/// response in memory. Compression is negotiated by the `Compress` middleware.
+fn stream_logs_response<I>(entries: I) -> HttpResponse
+where
+    I: IntoIterator<Item = LogEntryRequest>,
+    I::IntoIter: 'static,
+{
+    let entries = entries.into_iter().enumerate().map(|(i, entry)| {
+        let mut chunk = if i == 0 { vec![] } else { vec![b','] };
+        chunk.extend(to_wire_bytes(&entry)?);
+        Ok::<_, serde_json::Error>(Bytes::from(chunk))
+    });
+
+    let body = std::iter::once(Ok(Bytes::from_static(br#"{"success":true,"entries":["#)))
+        .chain(entries)
+        .chain(std::iter::once(Ok(Bytes::from_static(b"]}"))));
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .streaming(futures_util::stream::iter(body))
+}
+
+#[get("/metrics")]
+pub async fn get_metrics_endpoint() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+        .body(crate::render_metrics()))
+}
+
+/// Lets clients check that the writer is reachable without writing any entries
+#[post("/ping")]
+pub async fn ping_endpoint() -> Result<Json<Value>> {
     Ok(Json(serde_json::json!({"success": true})))
 }
 
@@ -30,16 +65,39 @@ pub async fn get_logs_endpoint(_query: web::Query<GetLogsQuery>) -> Result<Json<
 #[serde(rename_all = "camelCase")]
 pub struct CreateLogsQuery {}
 
+/// The IP, without the port, that entries in the request are recorded as coming from
+fn request_client_ip(req: &HttpRequest, trusted_proxies: &[crate::TrustedProxy]) -> String {
+    let Some(peer) = req.peer_addr() else {
+        return "unknown".to_string();
+    };
+    let header = |name| req.headers().get(name).and_then(|x| x.to_str().ok());
+
+    crate::client_ip(
+        peer.ip(),
+        header(actix_web::http::header::FORWARDED),
+        header(actix_web::http::header::X_FORWARDED_FOR),
+        trusted_proxies,
+    )
+    .to_string()
+}
+
+/// The trace ID from the configured [`crate::trace_id_header`], if the request has one
+fn request_trace_id(req: &HttpRequest) -> Option<String> {
+    let header = crate::trace_id_header();
+
+    req.headers()
+        .get(header.as_str())
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| crate::parse_trace_id(&header, x))
+}
+
 #[post("/logs")]
 pub async fn create_logs_endpoint(
     _query: web::Query<CreateLogsQuery>,
     req: HttpRequest,
-    payload: Json<Value>,
+    mut payload: web::Payload,
 ) -> Result<Json<Value>> {
-    let ip = req
-        .peer_addr()
-        .map(|x| x.to_string())
-        .unwrap_or("unknown".to_string());
+    let ip = request_client_ip(&req, crate::trusted_proxies());
 
     let user_agent = req
         .headers()
@@ -47,7 +105,270 @@ pub async fn create_logs_endpoint(
         .and_then(|x| x.to_str().ok().map(|x| x.to_string()))
         .unwrap_or("none".to_string());
 
-    crate::create_logs(payload.clone(), &ip, &user_agent).await?;
+    if let Some(seq) = req
+        .headers()
+        .get(crate::BATCH_SEQ_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok())
+    {
+        let source = match req
+            .headers()
+            .get(crate::CLIENT_INSTANCE_HEADER)
+            .and_then(|x| x.to_str().ok())
+        {
+            Some(instance) => instance.to_string(),
+            None => format!(
+                "{}|{user_agent}",
+                req.peer_addr()
+                    .map(|x| x.ip().to_string())
+                    .unwrap_or_default()
+            ),
+        };
+        crate::check_batch_seq(&source, seq);
+    }
 
-    Ok(Json(serde_json::json!({"success": true})))
+    let trace_id = request_trace_id(&req);
+
+    let content_type = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+
+    let max_payload_bytes = crate::max_payload_bytes();
+
+    let content_length = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<usize>().ok());
+
+    if content_length.is_some_and(|x| x > max_payload_bytes) {
+        return Err(crate::CreateLogsError::PayloadTooLarge.into());
+    }
+
+    if content_type.starts_with("application/x-ndjson") {
+        let entries = read_ndjson(payload, max_payload_bytes).await?;
+
+        let rejected =
+            crate::create_log_requests(entries, &ip, &user_agent, trace_id.as_deref()).await?;
+
+        return Ok(Json(
+            serde_json::json!({"success": true, "rejected": rejected}),
+        ));
+    }
+
+    let mut body = web::BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+
+        if body.len() + chunk.len() > max_payload_bytes {
+            return Err(crate::CreateLogsError::PayloadTooLarge.into());
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    let rejected = if content_type.starts_with("application/cbor") {
+        crate::create_cbor_logs(&body, &ip, &user_agent, trace_id.as_deref()).await?
+    } else {
+        let payload: Value = serde_json::from_slice(&body).map_err(|e| {
+            log::error!("Invalid payload: {e:?}");
+            crate::CreateLogsError::InvalidPayload
+        })?;
+
+        crate::create_logs(payload, &ip, &user_agent, trace_id.as_deref()).await?
+    };
+
+    Ok(Json(
+        serde_json::json!({"success": true, "rejected": rejected}),
+    ))
+}
+
+/// Parses an NDJSON body line by line as it arrives, failing as soon as more than `max_bytes`
+/// were received
+async fn read_ndjson<S, E>(mut payload: S, max_bytes: usize) -> Result<Vec<LogEntryRequest>>
+where
+    S: futures_util::Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<actix_web::Error>,
+{
+    let mut entries = vec![];
+    let mut pending = web::BytesMut::new();
+    let mut received = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(Into::into)?;
+        received += chunk.len();
+
+        if received > max_bytes {
+            return Err(crate::CreateLogsError::PayloadTooLarge.into());
+        }
+
+        pending.extend_from_slice(&chunk);
+
+        while let Some(index) = pending.iter().position(|x| *x == b'\n') {
+            let line = pending.split_to(index + 1);
+            entries.extend(crate::parse_ndjson_line(&line)?);
+        }
+    }
+
+    entries.extend(crate::parse_ndjson_line(&pending)?);
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, middleware::Compress, test, App};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn large_logs_responses_are_gzipped_when_accepted() {
+        let entries = (0..1000)
+            .map(
+                |i| serde_json::json!({"level": "INFO", "ts": i, "values": [format!("entry {i}")]}),
+            )
+            .collect::<Vec<_>>();
+        crate::remember_entries(crate::parse_logs(Value::Array(entries)).unwrap());
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Compress::default())
+                .service(get_logs_endpoint),
+        )
+        .await;
+
+        for (accept, encoding) in [("gzip", Some("gzip")), ("identity", None)] {
+            let request = test::TestRequest::get()
+                .uri("/logs")
+                .insert_header((header::ACCEPT_ENCODING, accept))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+
+            assert!(response.status().is_success());
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                "application/json"
+            );
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::CONTENT_ENCODING)
+                    .and_then(|x| x.to_str().ok()),
+                encoding
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_client_ip_never_includes_the_port() {
+        let request = |forwarded_for: &str| {
+            test::TestRequest::default()
+                .peer_addr("10.1.2.3:4711".parse().unwrap())
+                .insert_header((header::X_FORWARDED_FOR, forwarded_for))
+                .to_http_request()
+        };
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+
+        assert_eq!(request_client_ip(&request("198.51.100.1"), &[]), "10.1.2.3");
+        assert_eq!(
+            request_client_ip(&request("198.51.100.1"), &trusted),
+            "198.51.100.1"
+        );
+        assert_eq!(
+            request_client_ip(&request("198.51.100.1:80"), &trusted),
+            "198.51.100.1"
+        );
+        assert_eq!(
+            request_client_ip(&test::TestRequest::default().to_http_request(), &[]),
+            "unknown"
+        );
+    }
+
+    /// `body` split into chunks of `size` bytes, as it might arrive over the network
+    fn chunked(
+        body: &[u8],
+        size: usize,
+    ) -> futures_util::stream::Iter<std::vec::IntoIter<Result<Bytes, actix_web::Error>>> {
+        futures_util::stream::iter(
+            body.chunks(size)
+                .map(|x| Ok(Bytes::copy_from_slice(x)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn a_large_ndjson_stream_is_parsed_across_chunk_boundaries() {
+        let body = (0..5000)
+            .map(|i| format!(r#"{{"level":"INFO","ts":{i},"values":["entry {i}"]}}"#))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let entries = read_ndjson(chunked(body.as_bytes(), 1000), body.len())
+            .await
+            .unwrap();
+
+        assert_eq!(entries.len(), 5000);
+        assert!(entries.iter().enumerate().all(|(i, x)| x.ts == i));
+    }
+
+    #[actix_web::test]
+    async fn an_ndjson_stream_over_the_cap_is_too_large() {
+        let line = br#"{"level":"INFO","ts":1,"values":["entry"]}"#;
+        let body = [&line[..], b"\n"].concat().repeat(100);
+
+        let err = read_ndjson(chunked(&body, 64), body.len() - 1)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[actix_web::test]
+    async fn a_body_declared_over_the_cap_is_rejected_before_it_is_read() {
+        let app = test::init_service(App::new().service(create_logs_endpoint)).await;
+        let request = test::TestRequest::post()
+            .uri("/logs")
+            .insert_header((header::CONTENT_TYPE, "application/x-ndjson"))
+            .set_payload(vec![b' '; crate::max_payload_bytes() + 1])
+            .to_request();
+
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[actix_web::test]
+    async fn the_trace_id_header_is_attached_to_every_entry() {
+        let request = test::TestRequest::post()
+            .insert_header((crate::trace_id_header(), " abc-123 "))
+            .to_http_request();
+        let mut entries = crate::parse_logs(serde_json::json!([
+            {"level": "INFO", "ts": 1, "values": ["first"]},
+            {"level": "ERROR", "ts": 2, "values": [], "properties": {"user": "bob"}},
+        ]))
+        .unwrap();
+
+        crate::attach_trace_id(&mut entries, &request_trace_id(&request).unwrap());
+
+        assert!(entries
+            .iter()
+            .all(|x| { x.properties.as_ref().unwrap()["traceId"].to_string() == "abc-123" }));
+        assert_eq!(
+            entries[1].properties.as_ref().unwrap()["user"].to_string(),
+            "bob"
+        );
+        assert_eq!(
+            request_trace_id(&test::TestRequest::post().to_http_request()),
+            None
+        );
+    }
 }
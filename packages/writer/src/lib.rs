@@ -1,13 +1,28 @@
 #![cfg_attr(feature = "fail-on-warnings", deny(warnings))]
 
-use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorPayloadTooLarge};
 use aws_sdk_cloudwatchlogs::{
+    error::ProvideErrorMetadata as _,
     operation::{put_log_events::PutLogEventsError, RequestId},
     types::InputLogEvent,
 };
-use free_log_models::{LogEntry, LogEntryRequest};
+use aws_smithy_runtime_api::client::{orchestrator::HttpResponse, result::SdkError};
+use free_log_models::{
+    from_wire_bytes, LogBatchMeta, LogBatchRequest, LogComponent, LogEntry, LogEntryRequest,
+    LogQuery,
+};
 use serde_json::Value;
+use strum_macros::{AsRefStr, EnumString};
 use thiserror::Error;
+use tokio::sync::{OnceCell, Semaphore};
 
 pub mod api;
 
@@ -15,22 +30,19 @@ pub mod api;
 pub enum CreateLogsError {
     #[error("Invalid payload")]
     InvalidPayload,
+    #[error("Payload too large")]
+    PayloadTooLarge,
     #[error("MissingLogGroupConfiguration: {type:?}")]
     MissingLogGroupConfiguration { r#type: String },
     #[error("Failed to put logs")]
-    PutLogs(
-        #[from]
-        aws_smithy_runtime_api::client::result::SdkError<
-            PutLogEventsError,
-            aws_smithy_runtime_api::client::orchestrator::HttpResponse,
-        >,
-    ),
+    PutLogs(#[from] PutLogEventsSdkError),
 }
 
 impl From<CreateLogsError> for actix_web::Error {
     fn from(value: CreateLogsError) -> Self {
         match value {
             CreateLogsError::InvalidPayload => ErrorBadRequest("Invalid payload"),
+            CreateLogsError::PayloadTooLarge => ErrorPayloadTooLarge("Payload too large"),
             CreateLogsError::MissingLogGroupConfiguration { .. } => {
                 ErrorInternalServerError(value.to_string())
             }
@@ -42,18 +54,829 @@ impl From<CreateLogsError> for actix_web::Error {
     }
 }
 
+const DEFAULT_MAX_PAYLOAD_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_MAX_EVENTS_PER_BATCH: usize = 10_000;
+const DEFAULT_MAX_CONCURRENT_PUT_LOG_EVENTS: usize = 4;
+
+/// CloudWatch counts each event as its message's UTF-8 length plus this many bytes
+const EVENT_OVERHEAD_BYTES: usize = 26;
+const MAX_EVENT_BYTES: usize = 256 * 1024;
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+/// CloudWatch rejects batches whose events span more than 24 hours
+const MAX_BATCH_SPAN_MS: i64 = 24 * 60 * 60 * 1000;
+
+pub fn max_payload_bytes() -> usize {
+    std::env::var("MaxPayloadBytes")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES)
+}
+
+const DEFAULT_TRACE_ID_HEADER: &str = "X-Request-Id";
+
+pub fn trace_id_header() -> String {
+    std::env::var("TraceIdHeader").unwrap_or(DEFAULT_TRACE_ID_HEADER.to_string())
+}
+
+pub fn parse_trace_id(header: &str, value: &str) -> Option<String> {
+    let value = if header.eq_ignore_ascii_case("traceparent") {
+        // version-traceid-parentid-flags
+        value.split('-').nth(1)?
+    } else {
+        value
+    };
+
+    let value = value.trim();
+
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// A proxy address, or a CIDR range of them, whose forwarding headers are trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxy {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for TrustedProxy {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        // An invalid prefix length is left in place so that the address fails to parse
+        let (network, prefix_len) = match value.split_once('/') {
+            Some((network, prefix_len)) => match prefix_len.parse::<u8>() {
+                Ok(prefix_len) => (network, Some(prefix_len)),
+                Err(_) => (value, None),
+            },
+            None => (value, None),
+        };
+        let network = network.parse::<IpAddr>()?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+
+        Ok(Self {
+            network,
+            prefix_len: prefix_len.unwrap_or(max_len).min(max_len),
+        })
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = (prefix_len as usize / 8, prefix_len % 8);
+
+    network[..bytes] == ip[..bytes]
+        && (bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+}
+
+static TRUSTED_PROXIES: LazyLock<Vec<TrustedProxy>> = LazyLock::new(|| {
+    std::env::var("TrustedProxies")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .filter_map(|x| {
+            x.parse()
+                .inspect_err(|e| log::warn!("Invalid trusted proxy '{x}': {e:?}"))
+                .ok()
+        })
+        .collect()
+});
+
+/// The proxies whose `Forwarded` and `X-Forwarded-For` headers are trusted, from the
+/// comma-separated IPs and CIDR ranges in `TrustedProxies`, parsed once. Invalid entries are
+/// skipped.
+pub fn trusted_proxies() -> &'static [TrustedProxy] {
+    &TRUSTED_PROXIES
+}
+
+/// Parses a `Forwarded` or `X-Forwarded-For` node, which may be quoted, bracketed or include a
+/// port, e.g. `"[2001:db8::1]:4711"`
+fn parse_forwarded_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|x| x.ip()))
+        .or_else(|| value.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// The `for=` nodes of a `Forwarded` header, or the addresses in an `X-Forwarded-For` header,
+/// from the client to the nearest proxy. Unparsable nodes are `None`.
+fn forwarded_chain(forwarded: Option<&str>, forwarded_for: Option<&str>) -> Vec<Option<IpAddr>> {
+    if let Some(forwarded) = forwarded {
+        return forwarded
+            .split(',')
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_forwarded_node(value))
+                })
+            })
+            .collect();
+    }
+
+    forwarded_for
+        .into_iter()
+        .flat_map(|x| x.split(','))
+        .map(parse_forwarded_node)
+        .collect()
+}
+
+/// The client's IP: the peer itself unless it is a trusted proxy, in which case the forwarding
+/// headers are walked back from the nearest hop to the first address that is not a trusted
+/// proxy. Addresses before that were supplied by the client and can be spoofed, so they are
+/// ignored. `Forwarded` takes precedence over `X-Forwarded-For`.
+pub fn client_ip(
+    peer: IpAddr,
+    forwarded: Option<&str>,
+    forwarded_for: Option<&str>,
+    trusted: &[TrustedProxy],
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|x| x.contains(ip));
+
+    let mut client = peer;
+
+    if !is_trusted(client) {
+        return client;
+    }
+
+    for node in forwarded_chain(forwarded, forwarded_for).into_iter().rev() {
+        let Some(ip) = node else {
+            break;
+        };
+
+        client = ip;
+
+        if !is_trusted(client) {
+            break;
+        }
+    }
+
+    client
+}
+
+pub const BATCH_SEQ_HEADER: &str = "X-Batch-Seq";
+/// Identifies the client layer that sent a batch. Batch sequences are tracked per instance, or
+/// per peer IP and user agent for clients that don't send it.
+pub const CLIENT_INSTANCE_HEADER: &str = "X-Client-Instance";
+
+/// How many sources' batch sequences are tracked before the least recently seen is forgotten
+const MAX_BATCH_SEQ_SOURCES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchSeqIssue {
+    /// Batches between the last sequence number and this one never arrived
+    Gap { last: u64, missing: u64 },
+    /// The sequence number didn't increase, e.g. a reordered batch or a restarted client
+    OutOfOrder { last: u64 },
+}
+
+/// The last batch sequence number seen from each source, along with when it was last seen
+#[derive(Debug)]
+pub struct BatchSeqs {
+    last: HashMap<String, (u64, u64)>,
+    tick: u64,
+    max_sources: usize,
+}
+
+impl BatchSeqs {
+    pub fn new(max_sources: usize) -> Self {
+        Self {
+            last: HashMap::new(),
+            tick: 0,
+            max_sources: max_sources.max(1),
+        }
+    }
+
+    pub fn check(&mut self, source: &str, seq: u64) -> Option<BatchSeqIssue> {
+        self.tick += 1;
+
+        if !self.last.contains_key(source) && self.last.len() >= self.max_sources {
+            let oldest = self
+                .last
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(x, _)| x.clone());
+
+            if let Some(oldest) = oldest {
+                self.last.remove(&oldest);
+            }
+        }
+
+        match self.last.insert(source.to_string(), (seq, self.tick)) {
+            Some((last, _)) if seq > last + 1 => Some(BatchSeqIssue::Gap {
+                last,
+                missing: seq - last - 1,
+            }),
+            Some((last, _)) if seq <= last => Some(BatchSeqIssue::OutOfOrder { last }),
+            _ => None,
+        }
+    }
+}
+
+static LAST_BATCH_SEQS: LazyLock<Mutex<BatchSeqs>> =
+    LazyLock::new(|| Mutex::new(BatchSeqs::new(MAX_BATCH_SEQ_SOURCES)));
+
+/// Logs when a client's batch sequence numbers skip ahead or go backwards, which indicates lost
+/// or reordered batches (or a restarted client).
+pub fn check_batch_seq(source: &str, seq: u64) -> Option<BatchSeqIssue> {
+    let issue = LAST_BATCH_SEQS.lock().unwrap().check(source, seq);
+
+    match issue {
+        Some(BatchSeqIssue::Gap { last, missing }) => {
+            log::warn!(
+                "Batch sequence gap from {source}: missing {missing} batch(es) between {last} \
+                 and {seq}"
+            );
+        }
+        Some(BatchSeqIssue::OutOfOrder { last }) => {
+            log::warn!("Batch sequence out of order from {source}: {seq} after {last}");
+        }
+        None => {}
+    }
+
+    issue
+}
+
+/// How many distinct targets are counted separately. Entries from any further targets are counted
+/// under [`OTHER_TARGET`], since every target is a separate metric series.
+const MAX_COUNTED_TARGETS: usize = 1000;
+const OTHER_TARGET: &str = "other";
+
+static TARGET_COUNTS: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn count_targets(
+    counts: &mut HashMap<String, u64>,
+    entries: &[LogEntryRequest],
+    max_targets: usize,
+) {
+    for entry in entries {
+        let target = entry
+            .target
+            .as_deref()
+            .or(entry.module_path.as_deref())
+            .unwrap_or("unknown");
+
+        let target = if counts.contains_key(target) || counts.len() < max_targets {
+            target
+        } else {
+            OTHER_TARGET
+        };
+
+        *counts.entry(target.to_string()).or_default() += 1;
+    }
+}
+
+/// Renders the written entry counts per client target in the OpenMetrics text format
+pub fn render_metrics() -> String {
+    render_target_counts(&TARGET_COUNTS.lock().unwrap())
+}
+
+fn render_target_counts(counts: &HashMap<String, u64>) -> String {
+    let mut targets = counts.iter().collect::<Vec<_>>();
+    targets.sort();
+
+    let mut body = String::from(
+        "# TYPE free_log_entries_received counter\n\
+         # HELP free_log_entries_received Log entries received and written, by client target.\n",
+    );
+
+    for (target, count) in targets {
+        let target = target
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        body.push_str(&format!(
+            "free_log_entries_received_total{{target=\"{target}\"}} {count}\n"
+        ));
+    }
+
+    body.push_str("# EOF\n");
+    body
+}
+
+const DEFAULT_RECENT_ENTRIES: usize = 1000;
+
+/// How many of the most recently written entries GET /logs can return. Each instance keeps its
+/// own, so behind a load balancer or on Lambda it only returns the entries it received itself.
+pub fn recent_entries_capacity() -> usize {
+    std::env::var("RecentEntries")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_RECENT_ENTRIES)
+}
+
+static RECENT_ENTRIES: LazyLock<Mutex<VecDeque<LogEntryRequest>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::new()));
+
+fn remember_entries(entries: Vec<LogEntryRequest>) {
+    remember(
+        &mut RECENT_ENTRIES.lock().unwrap(),
+        entries,
+        recent_entries_capacity(),
+    );
+}
+
+fn remember(
+    recent: &mut VecDeque<LogEntryRequest>,
+    entries: Vec<LogEntryRequest>,
+    capacity: usize,
+) {
+    recent.extend(entries);
+
+    let excess = recent.len().saturating_sub(capacity);
+    recent.drain(..excess);
+}
+
+/// The recently written entries that match `query`, oldest first
+pub fn recent_entries(query: &LogQuery) -> Vec<LogEntryRequest> {
+    select_entries(&RECENT_ENTRIES.lock().unwrap(), query)
+}
+
+fn select_entries(recent: &VecDeque<LogEntryRequest>, query: &LogQuery) -> Vec<LogEntryRequest> {
+    recent
+        .iter()
+        .filter(|x| query.matches(x))
+        .take(query.limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect()
+}
+
+/// CloudWatch rejects events older than 14 days. An hour is kept in reserve so that an entry
+/// clamped to the edge of the window is still accepted once it reaches CloudWatch.
+const DEFAULT_MAX_TIMESTAMP_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60 - 60 * 60);
+/// CloudWatch rejects events more than 2 hours in the future
+const DEFAULT_MAX_TIMESTAMP_LEAD: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// The property that keeps an entry's original `ts` when [`ClockSkewAction`] changes it
+pub const ORIGINAL_TS_PROPERTY: &str = "originalTs";
+
+/// What to do with entries whose timestamps are outside the accepted window
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, AsRefStr)]
+#[strum(ascii_case_insensitive)]
+pub enum ClockSkewAction {
+    /// Moves the timestamp to the nearest edge of the window
+    #[default]
+    Clamp,
+    /// Replaces the timestamp with the server's time
+    Restamp,
+    /// Drops the entry
+    Reject,
+}
+
+pub fn clock_skew_action() -> ClockSkewAction {
+    std::env::var("ClockSkewAction")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_default()
+}
+
+/// How far in the past, in seconds, timestamps are accepted, capped an hour short of
+/// CloudWatch's 14 days
+pub fn max_timestamp_age() -> Duration {
+    std::env::var("MaxTimestampAgeSecs")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_MAX_TIMESTAMP_AGE)
+        .min(DEFAULT_MAX_TIMESTAMP_AGE)
+}
+
+/// How far in the future, in seconds, timestamps are accepted, capped at CloudWatch's 2 hours
+pub fn max_timestamp_lead() -> Duration {
+    std::env::var("MaxTimestampLeadSecs")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_MAX_TIMESTAMP_LEAD)
+        .min(DEFAULT_MAX_TIMESTAMP_LEAD)
+}
+
+/// Applies `action` to the entries whose `ts` is more than `max_age` before or `max_lead` after
+/// `now` (all in milliseconds since the Unix epoch). Changed timestamps are preserved in the
+/// [`ORIGINAL_TS_PROPERTY`] property. Also returns how many entries were dropped by
+/// [`ClockSkewAction::Reject`].
+pub fn correct_clock_skew(
+    entries: Vec<LogEntryRequest>,
+    now: usize,
+    max_age: Duration,
+    max_lead: Duration,
+    action: ClockSkewAction,
+) -> (Vec<LogEntryRequest>, usize) {
+    let min = now.saturating_sub(max_age.as_millis() as usize);
+    let max = now.saturating_add(max_lead.as_millis() as usize);
+    let count = entries.len();
+
+    let entries = entries
+        .into_iter()
+        .filter_map(|mut x| {
+            if (min..=max).contains(&x.ts) {
+                return Some(x);
+            }
+
+            log::warn!(
+                "Timestamp {} is outside {min}..={max}, applying {}",
+                x.ts,
+                action.as_ref()
+            );
+
+            let ts = match action {
+                ClockSkewAction::Clamp => x.ts.clamp(min, max),
+                ClockSkewAction::Restamp => now,
+                ClockSkewAction::Reject => return None,
+            };
+
+            x.properties.get_or_insert_with(Default::default).insert(
+                ORIGINAL_TS_PROPERTY.to_string(),
+                LogComponent::UInteger(x.ts),
+            );
+            x.ts = ts;
+
+            Some(x)
+        })
+        .collect::<Vec<_>>();
+    let rejected = count - entries.len();
+
+    (entries, rejected)
+}
+
+const DEFAULT_EMPTY_VALUES_PLACEHOLDER: &str = "(no message)";
+
+/// How entries without any `values` are written to CloudWatch
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString, AsRefStr)]
+#[strum(ascii_case_insensitive)]
+pub enum EmptyValues {
+    /// Writes them as `[]`
+    #[default]
+    Keep,
+    /// Leaves the values section out of the message
+    Omit,
+    /// Writes the `EmptyValuesPlaceholder` instead
+    Placeholder,
+}
+
+pub fn empty_values() -> EmptyValues {
+    std::env::var("EmptyValues")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_default()
+}
+
+pub fn empty_values_placeholder() -> String {
+    std::env::var("EmptyValuesPlaceholder")
+        .unwrap_or_else(|_| DEFAULT_EMPTY_VALUES_PLACEHOLDER.to_string())
+}
+
+/// The values section of an entry's message, or `None` if it should be left out
+pub fn values_section(
+    values: &[LogComponent],
+    empty: EmptyValues,
+    placeholder: &str,
+) -> Option<String> {
+    match empty {
+        EmptyValues::Omit if values.is_empty() => None,
+        EmptyValues::Placeholder if values.is_empty() => Some(placeholder.to_string()),
+        _ => Some(format!("{values:?}")),
+    }
+}
+
+pub fn max_events_per_batch() -> usize {
+    std::env::var("MaxEventsPerBatch")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_MAX_EVENTS_PER_BATCH)
+        .min(DEFAULT_MAX_EVENTS_PER_BATCH)
+}
+
+/// The size CloudWatch charges against its per-event and per-batch limits
+pub fn event_size(message: &str) -> usize {
+    message.len() + EVENT_OVERHEAD_BYTES
+}
+
+/// Truncates the message, on a character boundary, so that its event fits in `MAX_EVENT_BYTES`
+fn truncate_event_message(message: &mut String) {
+    let max = MAX_EVENT_BYTES - EVENT_OVERHEAD_BYTES;
+
+    if message.len() > max {
+        let mut end = max;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+}
+
+/// Splits `items`, sorted by `ts`, into consecutive chunks of at most `max_items` items whose
+/// sizes sum to at most `max_bytes` and whose timestamps are at most `max_span` apart. An item
+/// larger than `max_bytes` gets a chunk of its own.
+pub fn chunk_by_size<T>(
+    items: &[T],
+    max_items: usize,
+    max_bytes: usize,
+    max_span: i64,
+    size: impl Fn(&T) -> usize,
+    ts: impl Fn(&T) -> i64,
+) -> Vec<&[T]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (i, item) in items.iter().enumerate() {
+        let item_bytes = size(item);
+
+        if i > start
+            && (i - start >= max_items
+                || bytes + item_bytes > max_bytes
+                || ts(item) - ts(&items[start]) > max_span)
+        {
+            chunks.push(&items[start..i]);
+            start = i;
+            bytes = 0;
+        }
+
+        bytes += item_bytes;
+    }
+
+    if start < items.len() {
+        chunks.push(&items[start..]);
+    }
+
+    chunks
+}
+
+pub fn max_concurrent_put_log_events() -> usize {
+    std::env::var("MaxConcurrentPutLogEvents")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_PUT_LOG_EVENTS)
+}
+
+const DEFAULT_PUT_LOG_EVENTS_MAX_ATTEMPTS: u32 = 5;
+const PUT_LOG_EVENTS_BASE_BACKOFF: Duration = Duration::from_millis(100);
+const PUT_LOG_EVENTS_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+pub type PutLogEventsSdkError = SdkError<PutLogEventsError, HttpResponse>;
+
+pub fn put_log_events_max_attempts() -> u32 {
+    std::env::var("PutLogEventsMaxAttempts")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(DEFAULT_PUT_LOG_EVENTS_MAX_ATTEMPTS)
+}
+
+/// Throttling and transient network or service failures are worth retrying. Anything else, e.g.
+/// an invalid parameter or a missing log group, would fail the same way again.
+fn is_retryable(err: &PutLogEventsSdkError) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service_err) => {
+            let status = service_err.raw().status().as_u16();
+
+            service_err.err().is_service_unavailable_exception()
+                || matches!(
+                    service_err.err().code(),
+                    Some("ThrottlingException" | "LimitExceededException")
+                )
+                || status == 429
+                || status >= 500
+        }
+        _ => false,
+    }
+}
+
+/// Honors a `Retry-After` header (in seconds) on throttled responses
+fn retry_after(err: &PutLogEventsSdkError) -> Option<Duration> {
+    let SdkError::ServiceError(service_err) = err else {
+        return None;
+    };
+
+    service_err
+        .raw()
+        .headers()
+        .get("retry-after")
+        .and_then(|x| x.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .map(|x| x.min(PUT_LOG_EVENTS_MAX_BACKOFF))
+}
+
+fn put_log_events_backoff(attempt: u32) -> Duration {
+    PUT_LOG_EVENTS_BASE_BACKOFF
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(PUT_LOG_EVENTS_MAX_BACKOFF)
+}
+
+/// How long to wait before retrying after `err`, or `None` if it isn't worth retrying
+pub fn put_log_events_retry_delay(err: &PutLogEventsSdkError, attempt: u32) -> Option<Duration> {
+    is_retryable(err).then(|| retry_after(err).unwrap_or_else(|| put_log_events_backoff(attempt)))
+}
+
+/// Sends every chunk with `send`, at most `max_concurrent` at a time, for up to `max_attempts`
+/// rounds. Each round only resends the chunks that haven't been written yet, so one failing
+/// chunk never causes the others to be sent twice. `retry_delay` returns how long to wait after
+/// an error, or `None` to give up right away. This is on top of the SDK's own retries, which give
+/// up quickly under sustained throttling.
+pub async fn send_chunks<T, E, F, Fut>(
+    chunks: &[T],
+    max_concurrent: usize,
+    max_attempts: u32,
+    retry_delay: impl Fn(&E, u32) -> Option<Duration>,
+    send: F,
+) -> Result<(), E>
+where
+    T: Copy,
+    E: std::fmt::Debug,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let semaphore = Semaphore::new(max_concurrent.max(1));
+    let mut pending = (0..chunks.len()).collect::<Vec<_>>();
+    let mut attempt = 1;
+
+    loop {
+        let results = futures_util::future::join_all(pending.iter().map(|i| {
+            let semaphore = &semaphore;
+            let send = &send;
+
+            async move {
+                let _permit = semaphore.acquire().await;
+                send(chunks[*i]).await
+            }
+        }))
+        .await;
+
+        let mut failed = vec![];
+        let mut delay = Duration::ZERO;
+
+        for (index, result) in pending.into_iter().zip(results) {
+            let Err(err) = result else {
+                continue;
+            };
+
+            match retry_delay(&err, attempt).filter(|_| attempt < max_attempts) {
+                Some(chunk_delay) => {
+                    delay = delay.max(chunk_delay);
+                    failed.push(index);
+                }
+                None => {
+                    log::error!(
+                        "Giving up on chunk {index} of {} after attempt {attempt}",
+                        chunks.len()
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        log::warn!(
+            "Retrying {} of {} chunks in {delay:?} after attempt {attempt}",
+            failed.len(),
+            chunks.len()
+        );
+        tokio::time::sleep(delay).await;
+
+        pending = failed;
+        attempt += 1;
+    }
+}
+
+pub fn parse_ndjson_line(line: &[u8]) -> Result<Option<LogEntryRequest>, CreateLogsError> {
+    let line = line.trim_ascii();
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    from_wire_bytes(line).map(Some).map_err(|e| {
+        log::error!("Invalid payload: {e:?}");
+        CreateLogsError::InvalidPayload
+    })
+}
+
+/// Returns how many entries were rejected for their timestamps
 pub async fn create_logs<'a>(
     payload: Value,
     ip: &'a str,
     user_agent: &'a str,
-) -> Result<(), CreateLogsError> {
-    let entries: Vec<LogEntryRequest> = serde_json::from_value(payload).map_err(|e| {
+    trace_id: Option<&'a str>,
+) -> Result<usize, CreateLogsError> {
+    create_log_requests(parse_logs(payload)?, ip, user_agent, trace_id).await
+}
+
+/// Parses a batch, either an array of entries or an object with `meta` and `entries`
+pub fn parse_logs(payload: Value) -> Result<Vec<LogEntryRequest>, CreateLogsError> {
+    Ok(if payload.is_object() {
+        let batch: LogBatchRequest = serde_json::from_value(payload).map_err(|e| {
+            log::error!("Invalid payload: {e:?}");
+            CreateLogsError::InvalidPayload
+        })?;
+
+        apply_batch_meta(batch.entries, &batch.meta)
+    } else {
+        serde_json::from_value(payload).map_err(|e| {
+            log::error!("Invalid payload: {e:?}");
+            CreateLogsError::InvalidPayload
+        })?
+    })
+}
+
+fn apply_batch_meta(
+    mut entries: Vec<LogEntryRequest>,
+    meta: &LogBatchMeta,
+) -> Vec<LogEntryRequest> {
+    let meta = [
+        ("service", &meta.service),
+        ("version", &meta.version),
+        ("gitSha", &meta.git_sha),
+        ("environment", &meta.environment),
+    ];
+
+    for entry in entries.iter_mut() {
+        for (name, value) in meta.iter() {
+            if let Some(value) = value {
+                entry
+                    .properties
+                    .get_or_insert_with(Default::default)
+                    .entry(name.to_string())
+                    .or_insert_with(|| LogComponent::String(value.clone()));
+            }
+        }
+    }
+
+    entries
+}
+
+/// Returns how many entries were rejected for their timestamps
+pub async fn create_cbor_logs<'a>(
+    payload: &[u8],
+    ip: &'a str,
+    user_agent: &'a str,
+    trace_id: Option<&'a str>,
+) -> Result<usize, CreateLogsError> {
+    create_log_requests(parse_cbor_logs(payload)?, ip, user_agent, trace_id).await
+}
+
+/// Parses a CBOR-encoded batch in either of the shapes accepted by [`parse_logs`]
+pub fn parse_cbor_logs(payload: &[u8]) -> Result<Vec<LogEntryRequest>, CreateLogsError> {
+    let payload: Value = ciborium::from_reader(payload).map_err(|e| {
         log::error!("Invalid payload: {e:?}");
         CreateLogsError::InvalidPayload
     })?;
 
-    let entries = entries
-        .into_iter()
+    parse_logs(payload)
+}
+
+/// Writes the entries, returning how many were rejected for their timestamps
+pub async fn create_log_requests<'a>(
+    entries: Vec<LogEntryRequest>,
+    ip: &'a str,
+    user_agent: &'a str,
+    trace_id: Option<&'a str>,
+) -> Result<usize, CreateLogsError> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as usize;
+    let (mut entries, rejected) = correct_clock_skew(
+        entries,
+        now,
+        max_timestamp_age(),
+        max_timestamp_lead(),
+        clock_skew_action(),
+    );
+
+    if let Some(trace_id) = trace_id {
+        attach_trace_id(&mut entries, trace_id);
+    }
+
+    let log_entries = entries
+        .iter()
+        .cloned()
         .map(|x| LogEntry {
             level: x.level,
             values: x.values,
@@ -61,10 +884,63 @@ pub async fn create_logs<'a>(
             ip,
             user_agent,
             properties: x.properties,
+            fields: x.fields,
+            id: x.id,
         })
         .collect::<Vec<_>>();
 
-    create_log_entries(entries).await
+    create_log_entries(log_entries).await?;
+
+    count_targets(
+        &mut TARGET_COUNTS.lock().unwrap(),
+        &entries,
+        MAX_COUNTED_TARGETS,
+    );
+    remember_entries(entries);
+
+    Ok(rejected)
+}
+
+/// Sets the `traceId` property of every entry
+fn attach_trace_id(entries: &mut [LogEntryRequest], trace_id: &str) {
+    for entry in entries {
+        entry
+            .properties
+            .get_or_insert_with(Default::default)
+            .insert("traceId".to_string(), trace_id.into());
+    }
+}
+
+static CLOUDWATCH_CLIENT: OnceCell<aws_sdk_cloudwatchlogs::Client> = OnceCell::const_new();
+
+/// The shared client, built by the first request. Concurrent first requests wait on the same
+/// construction rather than each loading the AWS config.
+async fn cloudwatch_client() -> &'static aws_sdk_cloudwatchlogs::Client {
+    CLOUDWATCH_CLIENT.get_or_init(build_cloudwatch_client).await
+}
+
+async fn build_cloudwatch_client() -> aws_sdk_cloudwatchlogs::Client {
+    let config = load_aws_config(
+        std::env::var("AwsProfile").ok(),
+        std::env::var("AwsRegion").ok(),
+    )
+    .await;
+
+    aws_sdk_cloudwatchlogs::Client::new(&config)
+}
+
+/// Loads the AWS config from the environment, with the profile and region overridden when given
+async fn load_aws_config(profile: Option<String>, region: Option<String>) -> aws_config::SdkConfig {
+    let mut loader = aws_config::from_env();
+
+    if let Some(profile) = profile {
+        loader = loader.profile_name(profile);
+    }
+    if let Some(region) = region {
+        loader = loader.region(aws_config::Region::new(region));
+    }
+
+    loader.load().await
 }
 
 pub async fn create_log_entries(entries: Vec<LogEntry<'_>>) -> Result<(), CreateLogsError> {
@@ -79,26 +955,38 @@ pub async fn create_log_entries(entries: Vec<LogEntry<'_>>) -> Result<(), Create
         }
     })?;
 
-    let config = aws_config::load_from_env().await;
-    let client = aws_sdk_cloudwatchlogs::Client::new(&config);
+    let client = cloudwatch_client().await;
+
+    let empty_values = empty_values();
+    let placeholder = empty_values_placeholder();
 
-    let events = entries
+    let mut events = entries
         .iter()
         .map(|x| {
+            let mut message = format!("{}:\n\n\t", x.level.as_ref());
+
+            if let Some(values) = values_section(&x.values, empty_values, &placeholder) {
+                message.push_str(&values);
+                message.push_str("\n\n\t");
+            }
+
+            message.push_str(&format!(
+                "ip={}\n\n\t\
+                 user_agent={}\n\n\t\
+                 properties={:?}\n\n\t\
+                 fields={:?}",
+                x.ip, x.user_agent, x.properties, x.fields,
+            ));
+
+            if let Some(id) = &x.id {
+                message.push_str(&format!("\n\n\tid={id}"));
+            }
+
+            truncate_event_message(&mut message);
+
             InputLogEvent::builder()
                 .timestamp(x.ts as i64)
-                .message(format!(
-                    "{}:\n\n\t\
-                     {:?}\n\n\t\
-                     ip={}\n\n\t\
-                     user_agent={}\n\n\t\
-                     properties={:?}",
-                    x.level.as_ref(),
-                    x.values,
-                    x.ip,
-                    x.user_agent,
-                    x.properties,
-                ))
+                .message(message)
                 .build()
         })
         .collect::<Result<Vec<_>, _>>()
@@ -109,15 +997,775 @@ pub async fn create_log_entries(entries: Vec<LogEntry<'_>>) -> Result<(), Create
 
     log::debug!("Writing events ({}): {events:?}", events.len());
 
-    let output = client
-        .put_log_events()
-        .log_group_name(log_group_name)
-        .log_stream_name(log_stream_name)
-        .set_log_events(Some(events))
-        .send()
-        .await?;
+    // CloudWatch requires the events in a batch to be in chronological order
+    events.sort_by_key(|x| x.timestamp());
 
-    log::debug!("Successful request {:?}", output.request_id());
+    let chunks = chunk_by_size(
+        &events,
+        max_events_per_batch(),
+        MAX_BATCH_BYTES,
+        MAX_BATCH_SPAN_MS,
+        |x| event_size(x.message()),
+        |x| x.timestamp(),
+    );
+
+    send_chunks(
+        &chunks,
+        max_concurrent_put_log_events(),
+        put_log_events_max_attempts(),
+        put_log_events_retry_delay,
+        |chunk| {
+            let log_group_name = &log_group_name;
+            let log_stream_name = &log_stream_name;
+
+            async move {
+                let output = client
+                    .put_log_events()
+                    .log_group_name(log_group_name)
+                    .log_stream_name(log_stream_name)
+                    .set_log_events(Some(chunk.to_vec()))
+                    .send()
+                    .await?;
+
+                log::debug!("Successful request {:?}", output.request_id());
+
+                Ok(())
+            }
+        },
+    )
+    .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use free_log_models::LogLevel;
+
+    use super::*;
+
+    #[test]
+    fn cbor_batches_parse_the_same_as_json() {
+        let payloads = [
+            serde_json::json!([
+                {"level": "INFO", "ts": 1, "values": ["started", 3, 1.5, true]},
+                {"level": "ERROR", "ts": 2, "values": [], "properties": {"user": "bob"}},
+            ]),
+            serde_json::json!({
+                "meta": {"service": "api", "version": "1.2.3"},
+                "entries": [{"level": "WARN", "ts": 3, "values": ["slow"]}],
+            }),
+        ];
+
+        for payload in payloads {
+            let mut cbor = vec![];
+            ciborium::into_writer(&payload, &mut cbor).unwrap();
+
+            let from_json = parse_logs(payload).unwrap();
+            let from_cbor = parse_cbor_logs(&cbor).unwrap();
+
+            assert!(!from_json.is_empty());
+            assert_eq!(
+                serde_json::to_value(&from_cbor).unwrap(),
+                serde_json::to_value(&from_json).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_cbor_is_rejected() {
+        assert!(matches!(
+            parse_cbor_logs(&[0xff, 0x00]),
+            Err(CreateLogsError::InvalidPayload)
+        ));
+    }
+
+    fn retry_immediately(_: &String, _: u32) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+
+    #[tokio::test]
+    async fn only_failed_chunks_are_resent() {
+        let sends = Mutex::new(HashMap::<u32, usize>::new());
+
+        let result = send_chunks(&[1, 2, 3, 4], 2, 5, retry_immediately, |chunk| {
+            let sends = &sends;
+
+            async move {
+                let count = {
+                    let mut sends = sends.lock().unwrap();
+                    let count = sends.entry(chunk).or_default();
+                    *count += 1;
+                    *count
+                };
+
+                if chunk == 2 && count == 1 {
+                    Err("throttled".to_string())
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            sends.into_inner().unwrap(),
+            HashMap::from([(1, 1), (2, 2), (3, 1), (4, 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn chunks_in_flight_never_exceed_the_limit() {
+        let in_flight = std::sync::atomic::AtomicUsize::new(0);
+        let max_in_flight = std::sync::atomic::AtomicUsize::new(0);
+        let chunks = (0..20).collect::<Vec<u32>>();
+
+        let result = send_chunks(&chunks, 3, 1, retry_immediately, |_| {
+            let in_flight = &in_flight;
+            let max_in_flight = &max_in_flight;
+
+            async move {
+                let now = in_flight.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                Ok::<_, String>(())
+            }
+        })
+        .await;
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(max_in_flight.into_inner(), 3);
+    }
+
+    #[tokio::test]
+    async fn sending_gives_up_after_max_attempts() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = send_chunks(&[1], 1, 3, retry_immediately, |_| {
+            let attempts = &attempts;
+
+            async move {
+                attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err::<(), _>("throttled".to_string())
+            }
+        })
+        .await;
+
+        assert_eq!(result, Err("throttled".to_string()));
+        assert_eq!(attempts.into_inner(), 3);
+    }
+
+    #[tokio::test]
+    async fn non_retryable_errors_are_not_retried() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = send_chunks(
+            &[1],
+            1,
+            5,
+            |_: &String, _| None,
+            |_| {
+                let attempts = &attempts;
+
+                async move {
+                    attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    Err::<(), _>("access denied".to_string())
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(result, Err("access denied".to_string()));
+        assert_eq!(attempts.into_inner(), 1);
+    }
+
+    fn coded(code: &str) -> PutLogEventsError {
+        PutLogEventsError::generic(
+            aws_sdk_cloudwatchlogs::error::ErrorMetadata::builder()
+                .code(code)
+                .build(),
+        )
+    }
+
+    fn service_error(
+        err: PutLogEventsError,
+        status: u16,
+        retry_after: Option<&'static str>,
+    ) -> PutLogEventsSdkError {
+        let mut raw = HttpResponse::new(
+            status.try_into().unwrap(),
+            aws_smithy_types::body::SdkBody::empty(),
+        );
+
+        if let Some(retry_after) = retry_after {
+            raw.headers_mut().insert("retry-after", retry_after);
+        }
+
+        SdkError::service_error(err, raw)
+    }
+
+    fn invalid_parameter() -> PutLogEventsSdkError {
+        service_error(
+            PutLogEventsError::InvalidParameterException(
+                aws_sdk_cloudwatchlogs::types::error::InvalidParameterException::builder()
+                    .message("invalid sequence")
+                    .build(),
+            ),
+            400,
+            None,
+        )
+    }
+
+    #[test]
+    fn throttling_and_server_errors_are_retried_but_invalid_parameters_are_not() {
+        let retryable = |code, status| is_retryable(&service_error(coded(code), status, None));
+
+        assert!(retryable("ThrottlingException", 400));
+        assert!(retryable("LimitExceededException", 400));
+        assert!(retryable("TooManyRequests", 429));
+        assert!(retryable("InternalFailure", 500));
+        assert!(retryable("BadGateway", 502));
+        assert!(!retryable("ResourceNotFoundException", 400));
+        assert!(!is_retryable(&invalid_parameter()));
+    }
+
+    #[test]
+    fn retry_after_is_read_in_seconds_and_capped() {
+        let throttled = |x| service_error(coded("ThrottlingException"), 429, x);
+
+        assert_eq!(retry_after(&throttled(None)), None);
+        assert_eq!(retry_after(&throttled(Some("not a number"))), None);
+        assert_eq!(
+            retry_after(&throttled(Some(" 2 "))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            retry_after(&throttled(Some("60"))),
+            Some(PUT_LOG_EVENTS_MAX_BACKOFF)
+        );
+    }
+
+    #[test]
+    fn the_retry_delay_backs_off_unless_the_response_says_otherwise() {
+        let throttled = service_error(coded("ThrottlingException"), 400, None);
+
+        assert_eq!(
+            put_log_events_retry_delay(&throttled, 1),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            put_log_events_retry_delay(&throttled, 3),
+            Some(Duration::from_millis(400))
+        );
+        assert_eq!(
+            put_log_events_retry_delay(&throttled, 30),
+            Some(PUT_LOG_EVENTS_MAX_BACKOFF)
+        );
+        assert_eq!(
+            put_log_events_retry_delay(
+                &service_error(coded("ThrottlingException"), 429, Some("1")),
+                3
+            ),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(put_log_events_retry_delay(&invalid_parameter(), 1), None);
+    }
+
+    #[tokio::test]
+    async fn a_chunk_throttled_twice_is_written_on_the_third_attempt() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = send_chunks(&[1], 1, 5, put_log_events_retry_delay, |_| {
+            let attempts = &attempts;
+
+            async move {
+                if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 2 {
+                    Err(service_error(coded("ThrottlingException"), 400, Some("0")))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.into_inner(), 3);
+    }
+
+    #[tokio::test]
+    async fn an_invalid_parameter_is_not_retried() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = send_chunks(&[1], 1, 5, put_log_events_retry_delay, |_| {
+            let attempts = &attempts;
+
+            async move {
+                attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Err::<(), _>(invalid_parameter())
+            }
+        })
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(SdkError::ServiceError(x)) if x.err().is_invalid_parameter_exception()
+        ));
+        assert_eq!(attempts.into_inner(), 1);
+    }
+
+    #[tokio::test]
+    async fn an_explicit_region_overrides_the_environment() {
+        let config = load_aws_config(None, Some("eu-west-1".to_string())).await;
+        let client = aws_sdk_cloudwatchlogs::Client::new(&config);
+
+        assert_eq!(
+            client.config().region().map(ToString::to_string),
+            Some("eu-west-1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn the_client_is_built_once_for_concurrent_requests() {
+        // Keeps the config loader from looking the region up on the network
+        std::env::set_var("AwsRegion", "us-east-1");
+
+        let clients = futures_util::future::join_all((0..10).map(|_| cloudwatch_client())).await;
+
+        assert!(CLOUDWATCH_CLIENT.initialized());
+        assert!(clients.iter().all(|x| std::ptr::eq(*x, clients[0])));
+        assert!(std::ptr::eq(clients[0], cloudwatch_client().await));
+    }
+
+    #[test]
+    fn batch_sequence_gaps_are_detected_per_source() {
+        let mut seqs = BatchSeqs::new(10);
+
+        assert_eq!(seqs.check("a", 1), None);
+        assert_eq!(seqs.check("a", 2), None);
+        assert_eq!(seqs.check("b", 1), None);
+        assert_eq!(
+            seqs.check("a", 5),
+            Some(BatchSeqIssue::Gap {
+                last: 2,
+                missing: 2
+            })
+        );
+        assert_eq!(
+            seqs.check("a", 4),
+            Some(BatchSeqIssue::OutOfOrder { last: 5 })
+        );
+        assert_eq!(seqs.check("b", 2), None);
+    }
+
+    #[test]
+    fn the_least_recently_seen_source_is_forgotten() {
+        let mut seqs = BatchSeqs::new(2);
+
+        seqs.check("a", 1);
+        seqs.check("b", 1);
+        seqs.check("a", 2);
+        seqs.check("c", 1);
+
+        // "b" was evicted, so its next batch starts a new sequence rather than a gap
+        assert_eq!(seqs.check("b", 5), None);
+        // ...which in turn evicted "a"
+        assert_eq!(seqs.check("a", 7), None);
+        assert_eq!(seqs.check("b", 6), None);
+    }
+
+    fn entries_from(targets: &[&str]) -> Vec<LogEntryRequest> {
+        let entries = targets
+            .iter()
+            .map(|target| {
+                serde_json::json!({"level": "INFO", "ts": 1, "values": [], "target": target})
+            })
+            .collect();
+
+        parse_logs(Value::Array(entries)).unwrap()
+    }
+
+    #[test]
+    fn entries_are_counted_per_target() {
+        let mut counts = HashMap::new();
+
+        count_targets(
+            &mut counts,
+            &entries_from(&["app::db", "app::http", "app::db"]),
+            10,
+        );
+
+        let metrics = render_target_counts(&counts);
+
+        assert!(metrics.contains("free_log_entries_received_total{target=\"app::db\"} 2\n"));
+        assert!(metrics.contains("free_log_entries_received_total{target=\"app::http\"} 1\n"));
+        assert!(metrics.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn targets_beyond_the_limit_are_counted_as_other() {
+        let mut counts = HashMap::new();
+
+        count_targets(&mut counts, &entries_from(&["a", "b", "c", "a", "d"]), 2);
+
+        assert_eq!(
+            counts,
+            HashMap::from([
+                ("a".to_string(), 2),
+                ("b".to_string(), 1),
+                (OTHER_TARGET.to_string(), 2),
+            ])
+        );
+    }
+
+    #[test]
+    fn events_are_sized_with_cloudwatchs_overhead() {
+        assert_eq!(event_size(""), EVENT_OVERHEAD_BYTES);
+        assert_eq!(event_size("é"), 2 + EVENT_OVERHEAD_BYTES);
+    }
+
+    #[test]
+    fn messages_are_truncated_to_fit_an_event_on_a_char_boundary() {
+        let max = MAX_EVENT_BYTES - EVENT_OVERHEAD_BYTES;
+
+        let mut exact = "a".repeat(max);
+        truncate_event_message(&mut exact);
+        assert_eq!(exact.len(), max);
+
+        // The 2-byte character would straddle the limit, so it is dropped whole
+        let mut straddling = format!("{}é", "a".repeat(max - 1));
+        truncate_event_message(&mut straddling);
+        assert_eq!(straddling.len(), max - 1);
+        assert_eq!(event_size(&straddling), MAX_EVENT_BYTES - 1);
+    }
+
+    /// Chunks messages that all have the same timestamp
+    fn by_size<'a>(
+        messages: &'a [&'a str],
+        max_items: usize,
+        max_bytes: usize,
+    ) -> Vec<&'a [&'a str]> {
+        chunk_by_size(
+            messages,
+            max_items,
+            max_bytes,
+            MAX_BATCH_SPAN_MS,
+            |x| event_size(x),
+            |_| 0,
+        )
+    }
+
+    #[test]
+    fn batches_are_split_exactly_at_the_byte_budget() {
+        let budget = 4 * event_size("abc");
+
+        // Four events fill the budget exactly, so the fifth starts a new chunk
+        let messages = ["abc"; 5];
+        let chunks = by_size(&messages, 100, budget);
+        assert_eq!(chunks.iter().map(|x| x.len()).collect::<Vec<_>>(), [4, 1]);
+
+        // One byte over the budget moves the fourth event into the next chunk
+        let messages = ["abc", "abc", "abc", "abcd", "abc"];
+        let chunks = by_size(&messages, 100, budget);
+        assert_eq!(chunks.iter().map(|x| x.len()).collect::<Vec<_>>(), [3, 2]);
+    }
+
+    #[test]
+    fn batches_are_split_by_event_count_and_oversized_events_stand_alone() {
+        let messages = ["a"; 5];
+        let chunks = by_size(&messages, 2, MAX_BATCH_BYTES);
+        assert_eq!(
+            chunks.iter().map(|x| x.len()).collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+
+        let messages = ["a", "too large", "a"];
+        let chunks = by_size(&messages, 100, event_size("a") + 1);
+        assert_eq!(
+            chunks.iter().map(|x| x.len()).collect::<Vec<_>>(),
+            [1, 1, 1]
+        );
+        assert_eq!(chunks[1], ["too large"]);
+
+        assert!(by_size(&[], 100, 100).is_empty());
+    }
+
+    fn proxies(values: &[&str]) -> Vec<TrustedProxy> {
+        values.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn a_direct_client_is_its_own_peer() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+
+        assert_eq!(
+            client_ip(ip("203.0.113.7"), None, None, &trusted),
+            ip("203.0.113.7")
+        );
+
+        // Only trusted proxies can say who they forwarded for
+        assert_eq!(
+            client_ip(ip("203.0.113.7"), None, Some("198.51.100.1"), &trusted),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(ip("203.0.113.7"), None, Some("198.51.100.1"), &[]),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn a_trusted_proxy_forwards_the_client_ip() {
+        let trusted = proxies(&["10.0.0.0/8", "2001:db8::1"]);
+
+        assert_eq!(
+            client_ip(ip("10.1.2.3"), None, Some("198.51.100.1"), &trusted),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip(
+                ip("2001:db8::1"),
+                Some(r#"for="[2001:db8::7]:4711";proto=https"#),
+                Some("198.51.100.1"),
+                &trusted
+            ),
+            ip("2001:db8::7")
+        );
+        assert_eq!(
+            client_ip(
+                ip("10.1.2.3"),
+                None,
+                Some("198.51.100.1, 10.9.9.9"),
+                &trusted
+            ),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn addresses_spoofed_by_the_client_are_ignored() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+
+        // The client claims to be 1.1.1.1, which the proxy appends the real client to
+        assert_eq!(
+            client_ip(
+                ip("10.1.2.3"),
+                None,
+                Some("1.1.1.1, 198.51.100.1"),
+                &trusted
+            ),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip(ip("10.1.2.3"), None, Some("1.1.1.1, garbage"), &trusted),
+            ip("10.1.2.3")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_match_their_cidr_range() {
+        let range = "192.168.0.0/23".parse::<TrustedProxy>().unwrap();
+        let single = "192.168.0.1".parse::<TrustedProxy>().unwrap();
+
+        assert!(range.contains(ip("192.168.1.255")));
+        assert!(!range.contains(ip("192.168.2.0")));
+        assert!(!range.contains(ip("::1")));
+        assert!(single.contains(ip("192.168.0.1")));
+        assert!(!single.contains(ip("192.168.0.2")));
+        assert!("10.0.0.0/x".parse::<TrustedProxy>().is_err());
+    }
+
+    const HOUR: usize = 60 * 60 * 1000;
+    const NOW: usize = 1_000 * 24 * HOUR;
+
+    fn entries_at(timestamps: &[usize]) -> Vec<LogEntryRequest> {
+        let entries = timestamps
+            .iter()
+            .map(|ts| serde_json::json!({"level": "INFO", "ts": ts, "values": []}))
+            .collect();
+
+        parse_logs(Value::Array(entries)).unwrap()
+    }
+
+    fn skewed(action: ClockSkewAction) -> (Vec<LogEntryRequest>, usize) {
+        let too_old = NOW - 15 * 24 * HOUR;
+        let too_new = NOW + 3 * HOUR;
+
+        correct_clock_skew(
+            entries_at(&[too_old, NOW, too_new]),
+            NOW,
+            DEFAULT_MAX_TIMESTAMP_AGE,
+            DEFAULT_MAX_TIMESTAMP_LEAD,
+            action,
+        )
+    }
+
+    fn original_ts(entry: &LogEntryRequest) -> Option<usize> {
+        match entry.properties.as_ref()?.get(ORIGINAL_TS_PROPERTY)? {
+            LogComponent::UInteger(ts) => Some(*ts),
+            other => panic!("Unexpected {ORIGINAL_TS_PROPERTY}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn skewed_timestamps_are_clamped_inside_cloudwatchs_window() {
+        let (entries, rejected) = skewed(ClockSkewAction::Clamp);
+
+        assert_eq!(rejected, 0);
+        assert_eq!(
+            entries.iter().map(|x| x.ts).collect::<Vec<_>>(),
+            [NOW - 14 * 24 * HOUR + HOUR, NOW, NOW + 2 * HOUR]
+        );
+        assert_eq!(original_ts(&entries[0]), Some(NOW - 15 * 24 * HOUR));
+        assert_eq!(original_ts(&entries[1]), None);
+        assert_eq!(original_ts(&entries[2]), Some(NOW + 3 * HOUR));
+    }
+
+    #[test]
+    fn skewed_timestamps_are_restamped_with_the_server_time() {
+        let (entries, rejected) = skewed(ClockSkewAction::Restamp);
+
+        assert_eq!(rejected, 0);
+        assert!(entries.iter().all(|x| x.ts == NOW));
+        assert!(original_ts(&entries[0]).is_some());
+        assert_eq!(original_ts(&entries[1]), None);
+        assert!(original_ts(&entries[2]).is_some());
+    }
+
+    #[test]
+    fn skewed_timestamps_are_rejected_and_counted() {
+        let (entries, rejected) = skewed(ClockSkewAction::Reject);
+
+        assert_eq!(rejected, 2);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].ts, NOW);
+        assert_eq!(original_ts(&entries[0]), None);
+    }
+
+    #[test]
+    fn timestamps_at_the_edges_of_the_window_are_kept() {
+        let edges = [NOW - 14 * 24 * HOUR + HOUR, NOW + 2 * HOUR];
+
+        let (entries, rejected) = correct_clock_skew(
+            entries_at(&edges),
+            NOW,
+            DEFAULT_MAX_TIMESTAMP_AGE,
+            DEFAULT_MAX_TIMESTAMP_LEAD,
+            ClockSkewAction::Reject,
+        );
+
+        assert_eq!(rejected, 0);
+        assert_eq!(entries.iter().map(|x| x.ts).collect::<Vec<_>>(), edges);
+    }
+
+    fn recent_from(entries: &[(&str, usize, &str)]) -> VecDeque<LogEntryRequest> {
+        let entries = entries
+            .iter()
+            .map(|(level, ts, target)| {
+                serde_json::json!({"level": level, "ts": ts, "values": [], "target": target})
+            })
+            .collect();
+        let mut recent = VecDeque::new();
+
+        remember(&mut recent, parse_logs(Value::Array(entries)).unwrap(), 100);
+
+        recent
+    }
+
+    fn timestamps(entries: &[LogEntryRequest]) -> Vec<usize> {
+        entries.iter().map(|x| x.ts).collect()
+    }
+
+    #[test]
+    fn recent_entries_are_filtered_by_the_query() {
+        let recent = recent_from(&[
+            ("DEBUG", 1, "app"),
+            ("ERROR", 2, "app::db"),
+            ("WARN", 3, "application"),
+            ("WARN", 4, "app"),
+            ("ERROR", 5, "other"),
+        ]);
+
+        let query = LogQuery::new().min_level(LogLevel::Warn);
+        assert_eq!(timestamps(&select_entries(&recent, &query)), [2, 3, 4, 5]);
+
+        let query = LogQuery::new().target("app");
+        assert_eq!(timestamps(&select_entries(&recent, &query)), [1, 2, 4]);
+
+        let query = LogQuery::new().from(2).to(5);
+        assert_eq!(timestamps(&select_entries(&recent, &query)), [2, 3, 4]);
+
+        let query = LogQuery::new()
+            .min_level(LogLevel::Warn)
+            .target("app")
+            .limit(1);
+        assert_eq!(timestamps(&select_entries(&recent, &query)), [2]);
+
+        assert_eq!(
+            timestamps(&select_entries(&recent, &LogQuery::new())),
+            [1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn only_the_newest_entries_are_remembered() {
+        let mut recent = recent_from(&[("INFO", 1, "app"), ("INFO", 2, "app")]);
+
+        remember(
+            &mut recent,
+            Vec::from(recent_from(&[("INFO", 3, "app"), ("INFO", 4, "app")])),
+            3,
+        );
+
+        assert_eq!(
+            timestamps(&select_entries(&recent, &LogQuery::new())),
+            [2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn empty_values_are_kept_omitted_or_replaced() {
+        let placeholder = DEFAULT_EMPTY_VALUES_PLACEHOLDER;
+
+        assert_eq!(
+            values_section(&[], EmptyValues::Keep, placeholder).as_deref(),
+            Some("[]")
+        );
+        assert_eq!(values_section(&[], EmptyValues::Omit, placeholder), None);
+        assert_eq!(
+            values_section(&[], EmptyValues::Placeholder, placeholder).as_deref(),
+            Some("(no message)")
+        );
+    }
+
+    #[test]
+    fn non_empty_values_are_written_whatever_the_empty_values_setting() {
+        let values = [LogComponent::String("started".to_string())];
+
+        for empty in [
+            EmptyValues::Keep,
+            EmptyValues::Omit,
+            EmptyValues::Placeholder,
+        ] {
+            assert_eq!(
+                values_section(&values, empty, DEFAULT_EMPTY_VALUES_PLACEHOLDER).as_deref(),
+                Some("[started]")
+            );
+        }
+        assert_eq!("placeholder".parse(), Ok(EmptyValues::Placeholder));
+    }
+
+    #[test]
+    fn batches_never_span_more_than_24_hours() {
+        let day = MAX_BATCH_SPAN_MS;
+        let timestamps = [0, 1, day, day + 1, day + 2, 3 * day];
+        let chunks = chunk_by_size(&timestamps, 100, MAX_BATCH_BYTES, day, |_| 1, |x| *x);
+
+        assert_eq!(chunks, [&[0, 1, day][..], &[day + 1, day + 2], &[3 * day]]);
+    }
+}
@@ -30,6 +30,8 @@ async fn main() -> Result<(), Error> {
             .wrap(cors)
             .wrap(middleware::Compress::default())
             .service(api::get_logs_endpoint)
+            .service(api::get_metrics_endpoint)
+            .service(api::ping_endpoint)
             .service(api::create_logs_endpoint)
     };
 